@@ -0,0 +1,579 @@
+use std::collections::VecDeque;
+use std::env;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "alsa")]
+use alsa::pcm::{Access, Format, HwParams, PCM};
+#[cfg(feature = "alsa")]
+use alsa::{Direction, ValueOr};
+
+use rocket::tokio::sync::{mpsc, oneshot};
+
+use tracing::info;
+
+use yansi::Paint;
+
+use crate::{Audio, Color, SharedLights};
+
+// frames analyzed at once, about 23 ms at 44.1 kHz
+const AUDIO_BLOCK: usize = 1024;
+// blocks of energy history to compare beats against, about a second
+const AUDIO_HISTORY: usize = 43;
+const AUDIO_BEAT_THRESHOLD: f64 = 1.5;
+const AUDIO_BEAT_SPACING: Duration = Duration::from_millis(100);
+const AUDIO_BEAT_FLASH: Duration = Duration::from_millis(200);
+
+// crossover frequencies between the low, mid, and high bands
+const AUDIO_LOW_CUTOFF: f64 = 250.0;
+const AUDIO_HIGH_CUTOFF: f64 = 2500.0;
+
+// the longest format chunk, used by WAVE_FORMAT_EXTENSIBLE
+const WAV_FORMAT_MAX: u64 = 40;
+
+#[derive(Clone, Copy, Default)]
+pub struct AudioLevels {
+    // root mean square of the whole signal
+    level: f64,
+    // root mean square of the low, mid, and high bands
+    bands: [f64; 3],
+    beat: Option<Instant>,
+}
+
+impl AudioLevels {
+    // hue follows the balance of the bands from red (low) to blue (high) and brightness follows
+    // the level, flashing to full on every beat
+    pub fn color(&self, audio: &Audio) -> Color {
+        let total: f64 = self.bands.iter().sum();
+
+        let hue = if total > 0.0 {
+            (self.bands[1] * 120.0 + self.bands[2] * 240.0) / total
+        } else {
+            0.0
+        };
+
+        let flash = match self.beat {
+            Some(beat) => 1.0 - beat.elapsed().as_secs_f64() / AUDIO_BEAT_FLASH.as_secs_f64(),
+            None => 0.0,
+        };
+
+        let value = (self.level * audio.gain() * 4.0).max(flash).clamp(0.0, 1.0);
+
+        hsv(hue, 1.0, value)
+    }
+}
+
+fn hsv(hue: f64, saturation: f64, value: f64) -> Color {
+    let chroma = value * saturation;
+    let sector = (hue / 60.0).rem_euclid(6.0);
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+
+    let (red, green, blue) = match sector as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    let offset = value - chroma;
+    let channel = |channel: f64| ((channel + offset) * 255.0).round() as u8;
+
+    Color {
+        red: channel(red),
+        green: channel(green),
+        blue: channel(blue),
+    }
+}
+
+struct AudioAnalyzer {
+    low_coefficient: f64,
+    high_coefficient: f64,
+
+    low: f64,
+    high: f64,
+
+    history: VecDeque<f64>,
+    beat: Option<Instant>,
+}
+
+impl AudioAnalyzer {
+    fn new(rate: u32) -> AudioAnalyzer {
+        let coefficient = |cutoff: f64| 1.0 - (-2.0 * PI * cutoff / rate as f64).exp();
+
+        AudioAnalyzer {
+            low_coefficient: coefficient(AUDIO_LOW_CUTOFF),
+            high_coefficient: coefficient(AUDIO_HIGH_CUTOFF),
+
+            low: 0.0,
+            high: 0.0,
+
+            history: VecDeque::with_capacity(AUDIO_HISTORY),
+            beat: None,
+        }
+    }
+
+    fn analyze(&mut self, samples: &[f64]) -> AudioLevels {
+        let mut energy = 0.0;
+        let mut bands = [0.0; 3];
+
+        // one-pole low-pass filters split the signal into bands without a full spectrum
+        for sample in samples {
+            self.low += self.low_coefficient * (sample - self.low);
+            self.high += self.high_coefficient * (sample - self.high);
+
+            energy += sample * sample;
+            bands[0] += self.low * self.low;
+            bands[1] += (self.high - self.low) * (self.high - self.low);
+            bands[2] += (sample - self.high) * (sample - self.high);
+        }
+
+        let count = samples.len().max(1) as f64;
+
+        energy /= count;
+
+        // a beat is a block much louder than the last second
+        let average = if self.history.is_empty() {
+            f64::INFINITY
+        } else {
+            self.history.iter().sum::<f64>() / self.history.len() as f64
+        };
+
+        if energy > average * AUDIO_BEAT_THRESHOLD
+            && energy > 1e-4
+            && self
+                .beat
+                .is_none_or(|beat| beat.elapsed() >= AUDIO_BEAT_SPACING)
+        {
+            self.beat = Some(Instant::now());
+        }
+
+        if self.history.len() == AUDIO_HISTORY {
+            self.history.pop_front();
+        }
+
+        self.history.push_back(energy);
+
+        AudioLevels {
+            level: energy.sqrt(),
+            bands: bands.map(|band| (band / count).sqrt()),
+            beat: self.beat,
+        }
+    }
+}
+
+enum AudioInput {
+    Stream(Box<dyn Read + Send>),
+    #[cfg(feature = "alsa")]
+    Alsa(PCM, usize),
+}
+
+impl AudioInput {
+    // reads interleaved samples, returning how many were read and zero at the end of the stream
+    fn read(&mut self, samples: &mut [i16]) -> io::Result<usize> {
+        match self {
+            AudioInput::Stream(reader) => {
+                let mut bytes = vec![0u8; samples.len() * 2];
+                let mut filled = 0;
+
+                while filled < bytes.len() {
+                    match reader.read(&mut bytes[filled..])? {
+                        0 => break,
+                        size => filled += size,
+                    }
+                }
+
+                for (sample, bytes) in samples.iter_mut().zip(bytes[..filled].chunks_exact(2)) {
+                    *sample = i16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+
+                Ok(filled / 2)
+            }
+            #[cfg(feature = "alsa")]
+            AudioInput::Alsa(pcm, channels) => loop {
+                let frames = pcm.io_i16().map_err(io::Error::other)?.readi(samples);
+
+                match frames {
+                    Ok(frames) => {
+                        return Ok(frames * *channels);
+                    }
+                    Err(err) => {
+                        // recover from overruns instead of giving up on the capture
+                        pcm.try_recover(err, true).map_err(io::Error::other)?;
+                    }
+                }
+            },
+        }
+    }
+}
+
+fn wav_header(reader: &mut dyn Read) -> io::Result<(u32, u16)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut riff = [0u8; 8];
+    reader.read_exact(&mut riff)?;
+
+    if &riff[4..8] != b"WAVE" {
+        return Err(invalid("Not a WAVE file"));
+    }
+
+    let mut format = None;
+
+    loop {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        // chunks are padded to an even size
+        let padded = size + size % 2;
+
+        match &header[..4] {
+            b"fmt " => {
+                if size < 16 {
+                    return Err(invalid("WAVE format chunk too short"));
+                }
+
+                if size > WAV_FORMAT_MAX {
+                    return Err(invalid("WAVE format chunk too long"));
+                }
+
+                let mut chunk = [0u8; 16];
+                reader.read_exact(&mut chunk)?;
+
+                // the extensible format fields are not needed
+                io::copy(&mut (&mut *reader).take(padded - 16), &mut io::sink())?;
+
+                let tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+                let channels = u16::from_le_bytes([chunk[2], chunk[3]]);
+                let rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+                let bits = u16::from_le_bytes([chunk[14], chunk[15]]);
+
+                // plain or extensible pcm
+                if (tag != 1 && tag != 0xfffe) || bits != 16 {
+                    return Err(invalid("Only 16-bit PCM WAVE files are supported"));
+                }
+
+                format = Some((rate, channels));
+            }
+            b"data" => {
+                return format.ok_or_else(|| invalid("Missing WAVE format"));
+            }
+            _ => {
+                io::copy(&mut (&mut *reader).take(padded), &mut io::sink())?;
+            }
+        }
+    }
+}
+
+fn audio_open(source: &str) -> io::Result<(AudioInput, u32, u16)> {
+    let rate: u32 = match env::var("AUDIO_RATE") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 44100,
+    };
+
+    let channels: u16 = match env::var("AUDIO_CHANNELS") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 1,
+    };
+
+    if let Some(device) = source.strip_prefix("alsa") {
+        #[cfg(feature = "alsa")]
+        {
+            let device = device.strip_prefix(':').unwrap_or("default");
+            let device = if device.is_empty() { "default" } else { device };
+
+            let pcm = PCM::new(device, Direction::Capture, false).map_err(io::Error::other)?;
+
+            {
+                let params = HwParams::any(&pcm).map_err(io::Error::other)?;
+
+                params
+                    .set_channels(channels as u32)
+                    .map_err(io::Error::other)?;
+                params
+                    .set_rate(rate, ValueOr::Nearest)
+                    .map_err(io::Error::other)?;
+                params.set_format(Format::s16()).map_err(io::Error::other)?;
+                params
+                    .set_access(Access::RWInterleaved)
+                    .map_err(io::Error::other)?;
+
+                pcm.hw_params(&params).map_err(io::Error::other)?;
+            }
+
+            let rate = pcm
+                .hw_params_current()
+                .and_then(|params| params.get_rate())
+                .map_err(io::Error::other)?;
+
+            pcm.start().map_err(io::Error::other)?;
+
+            return Ok((AudioInput::Alsa(pcm, channels as usize), rate, channels));
+        }
+
+        #[cfg(not(feature = "alsa"))]
+        {
+            let _ = device;
+
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "ALSA capture requires the alsa feature",
+            ));
+        }
+    }
+
+    let mut reader: Box<dyn Read + Send> = match source {
+        "stdin" => Box::new(io::stdin()),
+        // fifos and files are read the same way
+        path => Box::new(File::open(path)?),
+    };
+
+    // wave files describe their own format, anything else is raw little-endian pcm
+    let mut magic = [0u8; 4];
+    let mut size = 0;
+
+    // pipes may hand over fewer bytes than asked for at a time
+    while size < magic.len() {
+        match reader.read(&mut magic[size..])? {
+            0 => break,
+            read => size += read,
+        }
+    }
+
+    if &magic[..size] == b"RIFF" {
+        let (rate, channels) = wav_header(&mut reader)?;
+
+        Ok((AudioInput::Stream(reader), rate, channels))
+    } else {
+        let reader = Cursor::new(magic[..size].to_vec()).chain(reader);
+
+        Ok((AudioInput::Stream(Box::new(reader)), rate, channels))
+    }
+}
+
+fn audio_input(source: &str, levels: mpsc::Sender<AudioLevels>) -> io::Result<()> {
+    let (mut input, rate, channels) = audio_open(source)?;
+
+    // playback is paced by the rate, which cannot be zero
+    if rate == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Audio sample rate must not be zero",
+        ));
+    }
+
+    println!(
+        "{}{} {} {}",
+        Paint::masked("🎧 "),
+        Paint::default("Audio input started from").bold(),
+        Paint::default(source).bold().underline(),
+        Paint::default(format!("({} Hz, {} channels)", rate, channels)).bold()
+    );
+
+    let channels = channels.max(1) as usize;
+
+    let mut analyzer = AudioAnalyzer::new(rate);
+
+    let mut samples = vec![0i16; AUDIO_BLOCK * channels];
+    let mut mono = Vec::with_capacity(AUDIO_BLOCK);
+
+    let start = Instant::now();
+    let mut frames: u64 = 0;
+
+    loop {
+        let size = input.read(&mut samples)?;
+
+        if size == 0 {
+            // quiet down once the stream is over
+            let _ = levels.blocking_send(AudioLevels::default());
+
+            return Ok(());
+        }
+
+        mono.clear();
+        mono.extend(samples[..size].chunks_exact(channels).map(|frame| {
+            frame.iter().map(|sample| *sample as f64).sum::<f64>()
+                / (channels as f64 * i16::MAX as f64)
+        }));
+
+        if levels.blocking_send(analyzer.analyze(&mono)).is_err() {
+            return Ok(());
+        }
+
+        // files are read much faster than they play, while live sources are never ahead
+        frames += mono.len() as u64;
+
+        let due = start + Duration::from_secs_f64(frames as f64 / rate as f64);
+        let now = Instant::now();
+
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+}
+
+// returns once a file or standard input ends, or with the error if the input fails
+pub async fn audio_server(lights: SharedLights) -> io::Result<()> {
+    let source = env::var("AUDIO_SOURCE").expect("Missing audio source");
+
+    let (sender, mut levels) = mpsc::channel(16);
+    let (result_sender, result) = oneshot::channel();
+
+    // reading audio blocks so it gets its own thread
+    thread::spawn(move || {
+        let _ = result_sender.send(audio_input(&source, sender));
+    });
+
+    while let Some(levels) = levels.recv().await {
+        lights.lock().await.set_audio(levels);
+    }
+
+    result
+        .await
+        .unwrap_or_else(|_err| Err(io::Error::other("audio input thread stopped")))?;
+
+    info!("Audio input ended");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the header after the RIFF magic, which audio_open has already read
+    fn wav(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0, 0, 0, 0];
+        bytes.extend_from_slice(b"WAVE");
+
+        for (id, data) in chunks {
+            bytes.extend_from_slice(*id);
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+
+            if data.len() % 2 == 1 {
+                bytes.push(0);
+            }
+        }
+
+        bytes
+    }
+
+    fn fmt(tag: u16, channels: u16, rate: u32, bits: u16) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&tag.to_le_bytes());
+        chunk.extend_from_slice(&channels.to_le_bytes());
+        chunk.extend_from_slice(&rate.to_le_bytes());
+        chunk.extend_from_slice(&(rate * channels as u32 * bits as u32 / 8).to_le_bytes());
+        chunk.extend_from_slice(&(channels * bits / 8).to_le_bytes());
+        chunk.extend_from_slice(&bits.to_le_bytes());
+        chunk
+    }
+
+    fn header(bytes: &[u8]) -> io::Result<(u32, u16)> {
+        wav_header(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn wav_header_pcm() {
+        let bytes = wav(&[(b"fmt ", fmt(1, 2, 48000, 16)), (b"data", vec![1, 2, 3, 4])]);
+
+        assert_eq!(header(&bytes).unwrap(), (48000, 2));
+    }
+
+    #[test]
+    fn wav_header_rejects_unsupported_formats() {
+        for format in [
+            fmt(1, 1, 44100, 8),
+            fmt(1, 1, 44100, 24),
+            fmt(3, 1, 44100, 16),
+        ] {
+            let bytes = wav(&[(b"fmt ", format), (b"data", vec![])]);
+
+            assert_eq!(
+                header(&bytes).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn wav_header_extended_format() {
+        // cbSize and the extensible fields after the plain format
+        let mut extensible = fmt(0xfffe, 1, 22050, 16);
+        extensible.resize(40, 0);
+
+        let bytes = wav(&[(b"fmt ", extensible), (b"data", vec![])]);
+
+        assert_eq!(header(&bytes).unwrap(), (22050, 1));
+
+        // odd sizes are followed by a pad byte
+        let mut odd = fmt(1, 1, 8000, 16);
+        odd.push(0);
+
+        let bytes = wav(&[(b"fmt ", odd), (b"data", vec![])]);
+
+        assert_eq!(header(&bytes).unwrap(), (8000, 1));
+    }
+
+    #[test]
+    fn wav_header_rejects_long_format() {
+        let mut long = fmt(1, 1, 44100, 16);
+        long.resize(WAV_FORMAT_MAX as usize + 2, 0);
+
+        let bytes = wav(&[(b"fmt ", long), (b"data", vec![])]);
+
+        assert_eq!(
+            header(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // only the declared size matters, so a huge one must not be allocated
+        let mut bytes = wav(&[]);
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        assert_eq!(
+            header(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn wav_header_skips_other_chunks() {
+        let bytes = wav(&[
+            (b"LIST", vec![7; 5]),
+            (b"fmt ", fmt(1, 1, 44100, 16)),
+            (b"fact", vec![0; 4]),
+            (b"data", vec![]),
+        ]);
+
+        assert_eq!(header(&bytes).unwrap(), (44100, 1));
+    }
+
+    #[test]
+    fn wav_header_rejects_missing_format() {
+        let bytes = wav(&[(b"data", vec![])]);
+
+        assert_eq!(
+            header(&bytes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn wav_header_truncated() {
+        let bytes = wav(&[(b"fmt ", fmt(1, 1, 44100, 16)), (b"data", vec![])]);
+
+        for size in [0, 6, 12, 20, 30] {
+            assert_eq!(
+                header(&bytes[..size]).unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+}
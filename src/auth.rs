@@ -0,0 +1,387 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use rocket::http::{RawStr, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Scope {
+    // read state and subscribe to changes
+    Read,
+    // also set solid colors
+    Color,
+    // everything, including patterns and settings
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "color" => Ok(Scope::Color),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("Unknown token scope: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Tokens {
+    scopes: HashMap<String, Scope>,
+}
+
+impl Tokens {
+    // tokens are configured as comma-separated token:scope pairs, and no tokens disables auth
+    pub fn from_env() -> Tokens {
+        let mut tokens = Tokens::default();
+
+        if let Ok(val) = env::var("API_TOKENS") {
+            for pair in val
+                .split(',')
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+            {
+                let (token, scope) = pair.rsplit_once(':').expect("Missing API token scope");
+
+                tokens
+                    .scopes
+                    .insert(String::from(token), scope.parse().unwrap());
+            }
+        }
+
+        tokens
+    }
+
+    pub fn scope(&self, token: Option<&str>) -> Result<Scope, Status> {
+        if self.scopes.is_empty() {
+            return Ok(Scope::Admin);
+        }
+
+        match token.and_then(|token| self.scopes.get(token)) {
+            Some(scope) => Ok(*scope),
+            None => Err(Status::Unauthorized),
+        }
+    }
+
+    pub fn authorize(&self, token: Option<&str>, required: Scope) -> Result<Scope, Status> {
+        let scope = self.scope(token)?;
+
+        if scope >= required {
+            Ok(scope)
+        } else {
+            Err(Status::Forbidden)
+        }
+    }
+}
+
+// bearer tokens from the header, or from the query string for browsers and websocket handshakes
+pub fn bearer<'a>(authorization: Option<&'a str>, query: Option<&'a str>) -> Option<Cow<'a, str>> {
+    match authorization.and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(token) => Some(Cow::Borrowed(token)),
+        None => query?
+            .split('&')
+            .find_map(|param| param.strip_prefix("token="))
+            // query values are percent-encoded, with + for spaces in forms
+            .map(|token| RawStr::new(token).url_decode_lossy()),
+    }
+}
+
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub enum ReadOnly {}
+
+impl RequiredScope for ReadOnly {
+    const SCOPE: Scope = Scope::Read;
+}
+
+pub enum ColorOnly {}
+
+impl RequiredScope for ColorOnly {
+    const SCOPE: Scope = Scope::Color;
+}
+
+pub enum Admin {}
+
+impl RequiredScope for Admin {
+    const SCOPE: Scope = Scope::Admin;
+}
+
+pub struct Authorized<S: RequiredScope> {
+    pub scope: Scope,
+    required: PhantomData<S>,
+}
+
+#[rocket::async_trait]
+impl<'r, S: RequiredScope> FromRequest<'r> for Authorized<S> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let tokens = match request.rocket().state::<Tokens>() {
+            Some(tokens) => tokens,
+            None => {
+                return Outcome::Error((Status::InternalServerError, ()));
+            }
+        };
+
+        let token = bearer(
+            request.headers().get_one("Authorization"),
+            request.uri().query().map(|query| query.as_str()),
+        );
+
+        match tokens.authorize(token.as_deref(), S::SCOPE) {
+            Ok(scope) => Outcome::Success(Authorized {
+                scope,
+                required: PhantomData,
+            }),
+            Err(status) => Outcome::Error((status, ())),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_err| format!("Invalid network address: {}", s))?;
+
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("Invalid network prefix: {}", s))?,
+            None => bits,
+        };
+
+        // peers are compared as ipv4 when they can be, so mapped networks must be too
+        match addr.to_canonical() {
+            IpAddr::V4(canonical) if addr.is_ipv6() && prefix >= 96 => Ok(Network {
+                addr: IpAddr::V4(canonical),
+                prefix: prefix - 96,
+            }),
+            _ => Ok(Network { addr, prefix }),
+        }
+    }
+}
+
+// comma-separated networks allowed to send to a server, or none to allow everyone
+pub fn allowlist_from_env(var: &str) -> Option<Vec<Network>> {
+    let val = env::var(var).ok()?;
+
+    Some(
+        val.split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(|network| network.parse().unwrap())
+            .collect(),
+    )
+}
+
+pub fn allowed(allowlist: &Option<Vec<Network>>, addr: IpAddr) -> bool {
+    match allowlist {
+        Some(networks) => networks.iter().any(|network| network.contains(addr)),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, addr: &str) -> bool {
+        network
+            .parse::<Network>()
+            .unwrap()
+            .contains(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn network_prefixes() {
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "11.1.2.3"));
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+    }
+
+    #[test]
+    fn network_everything() {
+        assert!(contains("0.0.0.0/0", "192.168.1.20"));
+        assert!(contains("::/0", "2001:db8::1"));
+        // the families still have to match
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(!contains("::/0", "192.168.1.20"));
+    }
+
+    #[test]
+    fn network_single_address() {
+        assert!(contains("192.168.1.20/32", "192.168.1.20"));
+        assert!(!contains("192.168.1.20/32", "192.168.1.21"));
+        assert!(contains("192.168.1.20", "192.168.1.20"));
+        assert!(!contains("192.168.1.20", "192.168.1.21"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1", "2001:db8::2"));
+    }
+
+    #[test]
+    fn network_ipv4_mapped() {
+        // dual-stack sockets report ipv4 peers as mapped ipv6 addresses
+        assert!(contains("192.168.1.0/24", "::ffff:192.168.1.20"));
+        assert!(!contains("192.168.1.0/24", "::ffff:192.168.2.20"));
+        assert!(contains("::ffff:192.168.1.0/120", "192.168.1.20"));
+        assert!(contains("::ffff:192.168.1.0/120", "::ffff:192.168.1.20"));
+        assert!(!contains("::ffff:192.168.1.0/120", "192.168.2.20"));
+    }
+
+    #[test]
+    fn network_invalid() {
+        for network in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "example.com",
+        ] {
+            assert!(network.parse::<Network>().is_err(), "{:?} parsed", network);
+        }
+    }
+
+    #[test]
+    fn allowlist() {
+        let allowlist = Some(vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]);
+
+        assert!(allowed(&allowlist, "10.1.2.3".parse().unwrap()));
+        assert!(allowed(&allowlist, "::1".parse().unwrap()));
+        assert!(!allowed(&allowlist, "127.0.0.1".parse().unwrap()));
+        assert!(!allowed(&Some(Vec::new()), "10.1.2.3".parse().unwrap()));
+        assert!(allowed(&None, "127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn authorize_scopes() {
+        let tokens = Tokens {
+            scopes: HashMap::from([
+                (String::from("reader"), Scope::Read),
+                (String::from("painter"), Scope::Color),
+                (String::from("admin"), Scope::Admin),
+            ]),
+        };
+
+        assert_eq!(
+            tokens.authorize(Some("reader"), Scope::Read),
+            Ok(Scope::Read)
+        );
+        assert_eq!(
+            tokens.authorize(Some("reader"), Scope::Color),
+            Err(Status::Forbidden)
+        );
+        assert_eq!(
+            tokens.authorize(Some("painter"), Scope::Color),
+            Ok(Scope::Color)
+        );
+        assert_eq!(
+            tokens.authorize(Some("painter"), Scope::Admin),
+            Err(Status::Forbidden)
+        );
+        assert_eq!(
+            tokens.authorize(Some("admin"), Scope::Admin),
+            Ok(Scope::Admin)
+        );
+        assert_eq!(
+            tokens.authorize(Some("other"), Scope::Read),
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            tokens.authorize(None, Scope::Read),
+            Err(Status::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn authorize_without_tokens() {
+        // auth is disabled when no tokens are configured
+        let tokens = Tokens::default();
+
+        assert_eq!(tokens.authorize(None, Scope::Admin), Ok(Scope::Admin));
+        assert_eq!(
+            tokens.authorize(Some("other"), Scope::Admin),
+            Ok(Scope::Admin)
+        );
+    }
+
+    #[test]
+    fn bearer_header() {
+        assert_eq!(bearer(Some("Bearer abc"), None).as_deref(), Some("abc"));
+        // the header takes precedence over the query string
+        assert_eq!(
+            bearer(Some("Bearer abc"), Some("token=def")).as_deref(),
+            Some("abc")
+        );
+        assert_eq!(bearer(Some("Basic abc"), None), None);
+    }
+
+    #[test]
+    fn bearer_query() {
+        assert_eq!(bearer(None, Some("token=abc")).as_deref(), Some("abc"));
+        assert_eq!(
+            bearer(None, Some("limit=5&token=abc")).as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            bearer(Some("Basic abc"), Some("token=abc")).as_deref(),
+            Some("abc")
+        );
+        assert_eq!(bearer(None, Some("limit=5")), None);
+        assert_eq!(bearer(None, None), None);
+    }
+
+    #[test]
+    fn bearer_query_decoded() {
+        assert_eq!(
+            bearer(None, Some("token=a%2Bb%3Dc")).as_deref(),
+            Some("a+b=c")
+        );
+        assert_eq!(bearer(None, Some("token=a+b")).as_deref(), Some("a b"));
+    }
+}
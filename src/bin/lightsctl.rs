@@ -0,0 +1,229 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::process;
+
+use rocket::serde::json::serde_json;
+use rocket::serde::json::serde_json::json;
+use rocket::serde::Deserialize;
+
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+use fooster_lights::{Color, Pattern};
+
+const USAGE: &str = "\
+Usage: lightsctl <command>
+
+Commands:
+  color             print the current color
+  color <#rrggbb>   set a solid color
+  pattern           print the current pattern
+  pattern <file>    set a pattern from a JSON file, or - for standard input
+  tail              print color and pattern changes as they happen
+
+Environment:
+  LIGHTS_URL        daemon address (default http://localhost:8000)
+  LIGHTS_TOKEN      API token, if the daemon requires one";
+
+#[derive(Deserialize)]
+#[serde(
+    crate = "rocket::serde",
+    rename_all = "snake_case",
+    tag = "type",
+    content = "content"
+)]
+enum Update {
+    Ack,
+    Error(String),
+    Color(Color),
+    Pattern(Pattern),
+}
+
+struct Client {
+    url: String,
+    token: Option<String>,
+}
+
+impl Client {
+    fn from_env() -> Client {
+        let url = env::var("LIGHTS_URL").unwrap_or_else(|_| String::from("http://localhost:8000"));
+
+        Client {
+            url: String::from(url.trim_end_matches('/')),
+            token: env::var("LIGHTS_TOKEN").ok(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = ureq::request(method, &format!("{}{}", self.url, path));
+
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    fn get_color(&self) -> Result<Color, String> {
+        self.request("GET", "/color")
+            .call()
+            .map_err(request_error)?
+            .into_json()
+            .map_err(|err| format!("invalid color from daemon: {}", err))
+    }
+
+    fn set_color(&self, color: Color) -> Result<(), String> {
+        self.request("PUT", "/color")
+            .send_json(color)
+            .map_err(request_error)?;
+
+        Ok(())
+    }
+
+    fn get_pattern(&self) -> Result<Pattern, String> {
+        self.request("GET", "/pattern")
+            .call()
+            .map_err(request_error)?
+            .into_json()
+            .map_err(|err| format!("invalid pattern from daemon: {}", err))
+    }
+
+    fn set_pattern(&self, pattern: &Pattern) -> Result<(), String> {
+        self.request("PUT", "/pattern")
+            .send_json(pattern)
+            .map_err(request_error)?;
+
+        Ok(())
+    }
+
+    // subscribes over the websocket endpoint and prints changes until the connection closes
+    fn tail(&self) -> Result<(), String> {
+        let url = match self.url.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}/ws", rest),
+            Some((_scheme, rest)) => format!("ws://{}/ws", rest),
+            None => format!("ws://{}/ws", self.url),
+        };
+
+        let mut request = url
+            .into_client_request()
+            .map_err(|err| format!("invalid daemon address: {}", err))?;
+
+        if let Some(token) = &self.token {
+            request.headers_mut().insert(
+                "Authorization",
+                format!("Bearer {}", token)
+                    .parse()
+                    .map_err(|_err| String::from("invalid API token"))?,
+            );
+        }
+
+        let (mut socket, _response) = tungstenite::connect(request)
+            .map_err(|err| format!("failed to connect to daemon: {}", err))?;
+
+        let subscribe = json!({
+            "type": "subscribe",
+            "content": ["color", "pattern"],
+        });
+
+        socket
+            .send(Message::Text(subscribe.to_string()))
+            .map_err(|err| format!("failed to subscribe: {}", err))?;
+
+        loop {
+            let text = match socket.read() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(_frame)) => return Ok(()),
+                Ok(_) => continue,
+                Err(err) => return Err(format!("connection to daemon lost: {}", err)),
+            };
+
+            // the current color is sent in the legacy format before the subscription applies
+            if let Ok(color) = serde_json::from_str::<Color>(&text) {
+                println!("color {}", color);
+                continue;
+            }
+
+            match serde_json::from_str::<Update>(&text) {
+                Ok(Update::Ack) => {}
+                Ok(Update::Error(err)) => return Err(err),
+                Ok(Update::Color(color)) => println!("color {}", color),
+                Ok(Update::Pattern(pattern)) => {
+                    println!("pattern {}", serde_json::to_string(&pattern).unwrap())
+                }
+                Err(err) => return Err(format!("invalid message from daemon: {}", err)),
+            }
+        }
+    }
+}
+
+fn request_error(err: ureq::Error) -> String {
+    match err {
+        ureq::Error::Status(code, response) => {
+            format!("daemon responded {} {}", code, response.status_text())
+        }
+        ureq::Error::Transport(transport) => format!("failed to reach daemon: {}", transport),
+    }
+}
+
+fn read_pattern(path: &str) -> Result<Pattern, String> {
+    let text = if path == "-" {
+        let mut text = String::new();
+
+        io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|err| format!("failed to read pattern: {}", err))?;
+
+        text
+    } else {
+        fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path, err))?
+    };
+
+    // checked here so mistakes show up with the file instead of as a bad request
+    serde_json::from_str(&text).map_err(|err| format!("invalid pattern in {}: {}", path, err))
+}
+
+fn run(client: &Client, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args[..] {
+        ["color"] => {
+            println!("{}", client.get_color()?);
+        }
+        ["color", color] => {
+            let color = color
+                .parse()
+                .map_err(|err| format!("invalid color {}: {}", color, err))?;
+
+            client.set_color(color)?;
+        }
+        ["pattern"] => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&client.get_pattern()?).unwrap()
+            );
+        }
+        ["pattern", path] => {
+            client.set_pattern(&read_pattern(path)?)?;
+        }
+        ["tail"] => {
+            client.tail()?;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(err) = run(&Client::from_env(), &args) {
+        eprintln!("lightsctl: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,453 @@
+use std::env;
+use std::net::Ipv4Addr;
+
+use rocket::tokio::net::UdpSocket;
+use rocket::tokio::time::Duration;
+
+use tracing::warn;
+
+use yansi::Paint;
+
+use crate::auth;
+use crate::history::Source;
+use crate::{Color, SharedLights};
+
+const E131_IDENTIFIER: &[u8] = b"ASC-E1.17\0\0\0";
+const E131_VECTOR_ROOT_DATA: u32 = 0x00000004;
+const E131_VECTOR_FRAMING_DATA: u32 = 0x00000002;
+const E131_VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const E131_OPTION_PREVIEW: u8 = 0x80;
+const E131_OPTION_TERMINATED: u8 = 0x40;
+
+const ARTNET_IDENTIFIER: &[u8] = b"Art-Net\0";
+const ARTNET_OP_DMX: u16 = 0x5000;
+
+struct DmxPatch {
+    // zero-based offset of the first channel in the universe
+    start: usize,
+    // red, green, blue, then optionally dimmer and strobe
+    channels: usize,
+    timeout: Duration,
+}
+
+impl DmxPatch {
+    fn from_env() -> DmxPatch {
+        let channel: usize = match env::var("DMX_CHANNEL") {
+            Ok(val) => val.parse().unwrap(),
+            Err(_err) => 1,
+        };
+
+        if !(1..=512).contains(&channel) {
+            panic!("DMX channel must be between 1 and 512");
+        }
+
+        let channels: usize = match env::var("DMX_CHANNELS") {
+            Ok(val) => val.parse().unwrap(),
+            Err(_err) => 5,
+        };
+
+        if channels != 3 && channels != 5 {
+            panic!("DMX channels must be 3 or 5");
+        }
+
+        let timeout = match env::var("DMX_TIMEOUT") {
+            Ok(val) => Duration::from_millis(val.parse().unwrap()),
+            // data loss timeout from E1.31
+            Err(_err) => Duration::from_millis(2500),
+        };
+
+        DmxPatch {
+            start: channel - 1,
+            channels,
+            timeout,
+        }
+    }
+
+    fn color(&self, data: &[u8]) -> Option<(Color, Option<Duration>)> {
+        let channels = data.get(self.start..self.start + self.channels)?;
+
+        let (dimmer, strobe) = match channels[..] {
+            [_red, _green, _blue, dimmer, strobe] => (dimmer, strobe),
+            _ => (255, 0),
+        };
+
+        let dim = |channel: u8| (channel as u16 * dimmer as u16 / 255) as u8;
+
+        let color = Color {
+            red: dim(channels[0]),
+            green: dim(channels[1]),
+            blue: dim(channels[2]),
+        };
+
+        // the bottom of the strobe range is open, the rest speeds up from 1 Hz to 20 Hz
+        let strobe = match strobe {
+            0..=9 => None,
+            strobe => Some(Duration::from_secs_f64(
+                1.0 / (1.0 + 19.0 * (strobe - 10) as f64 / 245.0),
+            )),
+        };
+
+        Some((color, strobe))
+    }
+}
+
+fn dmx_address() -> String {
+    match env::var("DMX_ADDRESS") {
+        Ok(val) => val,
+        Err(_err) => String::from(if cfg!(debug_assertions) {
+            "127.0.0.1"
+        } else {
+            "0.0.0.0"
+        }),
+    }
+}
+
+enum E131Packet<'a> {
+    Data(&'a [u8]),
+    Terminated,
+    Ignored,
+}
+
+fn e131_parse(packet: &[u8], universe: u16) -> Option<E131Packet<'_>> {
+    let u16_at = |index: usize| {
+        Some(u16::from_be_bytes(
+            packet.get(index..index + 2)?.try_into().ok()?,
+        ))
+    };
+    let u32_at = |index: usize| {
+        Some(u32::from_be_bytes(
+            packet.get(index..index + 4)?.try_into().ok()?,
+        ))
+    };
+
+    if packet.get(4..16)? != E131_IDENTIFIER
+        || u32_at(18)? != E131_VECTOR_ROOT_DATA
+        || u32_at(40)? != E131_VECTOR_FRAMING_DATA
+        || *packet.get(117)? != E131_VECTOR_DMP_SET_PROPERTY
+    {
+        return None;
+    }
+
+    let options = *packet.get(112)?;
+
+    if u16_at(113)? != universe || options & E131_OPTION_PREVIEW != 0 {
+        return Some(E131Packet::Ignored);
+    }
+
+    if options & E131_OPTION_TERMINATED != 0 {
+        return Some(E131Packet::Terminated);
+    }
+
+    // property values start with the start code, only plain dmx data is used
+    let count = u16_at(123)? as usize;
+    let values = packet.get(125..125 + count)?;
+
+    match values {
+        [0, data @ ..] => Some(E131Packet::Data(data)),
+        _ => Some(E131Packet::Ignored),
+    }
+}
+
+fn artnet_parse(packet: &[u8], universe: u16) -> Option<Option<&[u8]>> {
+    if packet.get(..8)? != ARTNET_IDENTIFIER {
+        return None;
+    }
+
+    let opcode = u16::from_le_bytes(packet.get(8..10)?.try_into().ok()?);
+
+    // polls and other operations are not answered
+    if opcode != ARTNET_OP_DMX {
+        return Some(None);
+    }
+
+    let port_address = u16::from_le_bytes(packet.get(14..16)?.try_into().ok()?);
+
+    if port_address != universe {
+        return Some(None);
+    }
+
+    let length = u16::from_be_bytes(packet.get(16..18)?.try_into().ok()?) as usize;
+
+    Some(Some(packet.get(18..18 + length)?))
+}
+
+async fn dmx_apply(data: &[u8], patch: &DmxPatch, lights: &SharedLights, source: Source) {
+    match patch.color(data) {
+        Some((color, strobe)) => {
+            lights
+                .lock()
+                .await
+                .set_realtime(color, strobe, Some(patch.timeout), source);
+        }
+        None => {
+            warn!(
+                channel = patch.start + 1,
+                channels = data.len(),
+                "DMX universe too short for channel"
+            );
+        }
+    }
+}
+
+pub async fn e131_server(lights: SharedLights) {
+    let universe: u16 = env::var("E131_UNIVERSE")
+        .expect("Missing E1.31 universe")
+        .parse()
+        .unwrap();
+
+    let port: u16 = match env::var("E131_PORT") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 5568,
+    };
+
+    let patch = DmxPatch::from_env();
+
+    let allowlist = auth::allowlist_from_env("DMX_ALLOW");
+
+    let socket = UdpSocket::bind((dmx_address(), port))
+        .await
+        .expect("Failed to bind UDP E1.31 address");
+
+    // consoles usually multicast each universe to its own group
+    let group = Ipv4Addr::new(239, 255, (universe >> 8) as u8, universe as u8);
+
+    match socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED) {
+        Ok(()) => {}
+        Err(err) => {
+            warn!(%group, error = %err, "Failed to join E1.31 multicast group");
+        }
+    }
+
+    println!(
+        "{}{} {} {}",
+        Paint::masked("🎚  "),
+        Paint::default("E1.31 server started on").bold(),
+        Paint::default(socket.local_addr().unwrap())
+            .bold()
+            .underline(),
+        Paint::default(format!("(universe {})", universe)).bold()
+    );
+
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((_size, addr)) if !auth::allowed(&allowlist, addr.ip()) => {
+                warn!(source = %addr, "Rejected E1.31 packet");
+            }
+            Ok((size, addr)) => match e131_parse(&buffer[..size], universe) {
+                Some(E131Packet::Data(data)) => {
+                    dmx_apply(data, &patch, &lights, Source::Dmx(addr)).await;
+                }
+                Some(E131Packet::Terminated) => {
+                    lights.lock().await.clear_realtime(Source::Dmx(addr));
+                }
+                Some(E131Packet::Ignored) => {}
+                None => {
+                    warn!(source = %addr, "Unexpected E1.31 packet");
+                }
+            },
+            Err(err) => {
+                warn!(error = %err, "Error receiving from DMX socket");
+            }
+        }
+    }
+}
+
+pub async fn artnet_server(lights: SharedLights) {
+    let universe: u16 = env::var("ARTNET_UNIVERSE")
+        .expect("Missing Art-Net universe")
+        .parse()
+        .unwrap();
+
+    let port: u16 = match env::var("ARTNET_PORT") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 6454,
+    };
+
+    let patch = DmxPatch::from_env();
+
+    let allowlist = auth::allowlist_from_env("DMX_ALLOW");
+
+    let socket = UdpSocket::bind((dmx_address(), port))
+        .await
+        .expect("Failed to bind UDP Art-Net address");
+
+    println!(
+        "{}{} {} {}",
+        Paint::masked("🎚  "),
+        Paint::default("Art-Net server started on").bold(),
+        Paint::default(socket.local_addr().unwrap())
+            .bold()
+            .underline(),
+        Paint::default(format!("(universe {})", universe)).bold()
+    );
+
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((_size, addr)) if !auth::allowed(&allowlist, addr.ip()) => {
+                warn!(source = %addr, "Rejected Art-Net packet");
+            }
+            Ok((size, addr)) => match artnet_parse(&buffer[..size], universe) {
+                Some(Some(data)) => {
+                    dmx_apply(data, &patch, &lights, Source::Dmx(addr)).await;
+                }
+                Some(None) => {}
+                None => {
+                    warn!(source = %addr, "Unexpected Art-Net packet");
+                }
+            },
+            Err(err) => {
+                warn!(error = %err, "Error receiving from DMX socket");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn e131_packet(universe: u16, options: u8, values: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+
+        // root layer
+        packet.extend_from_slice(&0x0010u16.to_be_bytes());
+        packet.extend_from_slice(&0x0000u16.to_be_bytes());
+        packet.extend_from_slice(E131_IDENTIFIER);
+        packet.extend_from_slice(&(0x7000 | (109 + values.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&E131_VECTOR_ROOT_DATA.to_be_bytes());
+        packet.extend_from_slice(&[0x42; 16]);
+
+        // framing layer
+        packet.extend_from_slice(&(0x7000 | (87 + values.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&E131_VECTOR_FRAMING_DATA.to_be_bytes());
+        let mut name = [0u8; 64];
+        name[..4].copy_from_slice(b"test");
+        packet.extend_from_slice(&name);
+        packet.push(100);
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        packet.push(1);
+        packet.push(options);
+        packet.extend_from_slice(&universe.to_be_bytes());
+
+        // dmp layer
+        packet.extend_from_slice(&(0x7000 | (10 + values.len()) as u16).to_be_bytes());
+        packet.push(E131_VECTOR_DMP_SET_PROPERTY);
+        packet.push(0xa1);
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        packet.extend_from_slice(values);
+
+        packet
+    }
+
+    fn artnet_packet(opcode: u16, port_address: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+
+        packet.extend_from_slice(ARTNET_IDENTIFIER);
+        packet.extend_from_slice(&opcode.to_le_bytes());
+        packet.extend_from_slice(&14u16.to_be_bytes());
+        packet.push(0);
+        packet.push(0);
+        packet.extend_from_slice(&port_address.to_le_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+
+        packet
+    }
+
+    #[test]
+    fn e131_data() {
+        let packet = e131_packet(1, 0, &[0, 255, 128, 0]);
+
+        assert_eq!(packet.len(), 129);
+        assert!(matches!(
+            e131_parse(&packet, 1),
+            Some(E131Packet::Data([255, 128, 0]))
+        ));
+    }
+
+    #[test]
+    fn e131_ignored() {
+        // another universe, preview data, and a non-zero start code
+        for packet in [
+            e131_packet(2, 0, &[0, 255, 128, 0]),
+            e131_packet(1, E131_OPTION_PREVIEW, &[0, 255, 128, 0]),
+            e131_packet(1, 0, &[0xdd, 255, 128, 0]),
+        ] {
+            assert!(matches!(e131_parse(&packet, 1), Some(E131Packet::Ignored)));
+        }
+    }
+
+    #[test]
+    fn e131_terminated() {
+        let packet = e131_packet(1, E131_OPTION_TERMINATED, &[0]);
+
+        assert!(matches!(
+            e131_parse(&packet, 1),
+            Some(E131Packet::Terminated)
+        ));
+    }
+
+    #[test]
+    fn e131_malformed() {
+        let packet = e131_packet(1, 0, &[0, 255, 128, 0]);
+
+        // truncated data, a wrong identifier, and nothing at all
+        assert!(e131_parse(&packet[..packet.len() - 1], 1).is_none());
+        assert!(e131_parse(&artnet_packet(ARTNET_OP_DMX, 1, &[255]), 1).is_none());
+        assert!(e131_parse(&[], 1).is_none());
+    }
+
+    #[test]
+    fn artnet_data() {
+        let packet = artnet_packet(ARTNET_OP_DMX, 0x0102, &[255, 128, 0, 0]);
+
+        assert_eq!(
+            artnet_parse(&packet, 0x0102),
+            Some(Some(&[255, 128, 0, 0][..]))
+        );
+    }
+
+    #[test]
+    fn artnet_ignored() {
+        // another universe and a poll
+        assert_eq!(
+            artnet_parse(&artnet_packet(ARTNET_OP_DMX, 2, &[255]), 1),
+            Some(None)
+        );
+        assert_eq!(artnet_parse(&artnet_packet(0x2000, 1, &[]), 1), Some(None));
+    }
+
+    #[test]
+    fn artnet_malformed() {
+        let packet = artnet_packet(ARTNET_OP_DMX, 1, &[255, 128, 0, 0]);
+
+        assert_eq!(artnet_parse(&packet[..packet.len() - 1], 1), None);
+        assert_eq!(artnet_parse(&e131_packet(1, 0, &[0, 255]), 1), None);
+        assert_eq!(artnet_parse(b"Art-Net", 1), None);
+    }
+
+    #[test]
+    fn patch_color() {
+        let patch = DmxPatch {
+            start: 1,
+            channels: 5,
+            timeout: Duration::from_secs(1),
+        };
+
+        let (color, strobe) = patch.color(&[0, 255, 128, 0, 128, 0]).unwrap();
+
+        assert_eq!((color.red, color.green, color.blue), (128, 64, 0));
+        assert!(strobe.is_none());
+
+        let (_color, strobe) = patch.color(&[0, 255, 128, 0, 255, 255]).unwrap();
+
+        assert_eq!(strobe, Some(Duration::from_millis(50)));
+        assert!(patch.color(&[0, 255, 128, 0, 255]).is_none());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
+
+use rocket::serde::json::serde_json;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::State;
+
+use tracing::warn;
+
+use crate::auth::{Authorized, ReadOnly};
+use crate::{Pattern, SharedLights};
+
+const HISTORY_LIMIT: usize = 1000;
+
+// interface a change came through, with the peer where there is one
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(
+    crate = "rocket::serde",
+    rename_all = "lowercase",
+    tag = "type",
+    content = "content"
+)]
+pub enum Source {
+    Http(SocketAddr),
+    Form(SocketAddr),
+    WebSocket(SocketAddr),
+    Osc(SocketAddr),
+    Wled(SocketAddr),
+    // e1.31 and art-net
+    Dmx(SocketAddr),
+    Opc(SocketAddr),
+    Mqtt,
+    Midi,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Http(peer) => write!(f, "http {}", peer),
+            Source::Form(peer) => write!(f, "form {}", peer),
+            Source::WebSocket(peer) => write!(f, "websocket {}", peer),
+            Source::Osc(peer) => write!(f, "osc {}", peer),
+            Source::Wled(peer) => write!(f, "wled {}", peer),
+            Source::Dmx(peer) => write!(f, "dmx {}", peer),
+            Source::Opc(peer) => write!(f, "opc {}", peer),
+            Source::Mqtt => write!(f, "mqtt"),
+            Source::Midi => write!(f, "midi"),
+        }
+    }
+}
+
+impl Source {
+    fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Source::Http(peer)
+            | Source::Form(peer)
+            | Source::WebSocket(peer)
+            | Source::Osc(peer)
+            | Source::Wled(peer)
+            | Source::Dmx(peer)
+            | Source::Opc(peer) => Some(peer.ip()),
+            Source::Mqtt | Source::Midi => None,
+        }
+    }
+
+    // the same interface from the same host, since clients send from a new port per connection
+    pub fn same_origin(&self, other: &Source) -> bool {
+        mem::discriminant(self) == mem::discriminant(other) && self.ip() == other.ip()
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Entry {
+    // milliseconds since the unix epoch
+    time: u64,
+    source: Source,
+    #[serde(flatten)]
+    event: Event,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum Event {
+    Pattern { previous: Pattern, pattern: Pattern },
+    // a realtime protocol took over the lights, or released them back to the pattern
+    Realtime { realtime: bool },
+}
+
+pub struct History {
+    entries: VecDeque<Entry>,
+    limit: usize,
+    // entries are written on a separate thread so the lights are never held up by the disk
+    writer: Option<mpsc::Sender<Entry>>,
+}
+
+struct HistoryFile {
+    path: PathBuf,
+    file: File,
+    // lines in the file, which is compacted down to the limit once it holds twice as many
+    written: usize,
+    entries: VecDeque<Entry>,
+    limit: usize,
+}
+
+impl History {
+    // history is kept in memory only unless a file is configured
+    pub fn from_env() -> History {
+        let limit = match env::var("HISTORY_LIMIT") {
+            Ok(val) => val.parse().unwrap(),
+            Err(_) => HISTORY_LIMIT,
+        };
+
+        let mut history = History {
+            entries: VecDeque::with_capacity(limit),
+            limit,
+            writer: None,
+        };
+
+        if let Ok(path) = env::var("HISTORY_FILE") {
+            let path = PathBuf::from(path);
+            let mut written = 0;
+
+            match File::open(&path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines() {
+                        let line = line.expect("Failed to read history file");
+
+                        match serde_json::from_str(&line) {
+                            Ok(entry) => push(&mut history.entries, limit, entry),
+                            Err(err) => {
+                                warn!(error = %err, "Skipping invalid history entry");
+                            }
+                        }
+
+                        written += 1;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => panic!("Failed to open history file: {}", err),
+            }
+
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .expect("Failed to open history file");
+
+            let mut history_file = HistoryFile {
+                path,
+                file,
+                written,
+                entries: history.entries.clone(),
+                limit,
+            };
+
+            let (sender, receiver) = mpsc::channel::<Entry>();
+
+            thread::spawn(move || {
+                while let Ok(entry) = receiver.recv() {
+                    history_file.write(entry);
+                }
+            });
+
+            history.writer = Some(sender);
+        }
+
+        history
+    }
+
+    pub fn record(&mut self, source: Source, previous: &Pattern, pattern: &Pattern) {
+        self.record_event(
+            source,
+            Event::Pattern {
+                previous: previous.clone(),
+                pattern: pattern.clone(),
+            },
+        );
+    }
+
+    pub fn record_realtime(&mut self, source: Source, realtime: bool) {
+        self.record_event(source, Event::Realtime { realtime });
+    }
+
+    fn record_event(&mut self, source: Source, event: Event) {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64);
+
+        let entry = Entry {
+            time,
+            source,
+            event,
+        };
+
+        if let Some(writer) = &self.writer {
+            if writer.send(entry.clone()).is_err() {
+                warn!("History writer stopped, entry not written");
+            }
+        }
+
+        push(&mut self.entries, self.limit, entry);
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &Entry> + ExactSizeIterator {
+        self.entries.iter()
+    }
+}
+
+impl HistoryFile {
+    fn write(&mut self, entry: Entry) {
+        // a failed write loses the entry on disk but must not stop the lights from changing
+        match writeln!(self.file, "{}", serde_json::to_string(&entry).unwrap()) {
+            Ok(()) => {
+                self.written += 1;
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to write history entry");
+            }
+        }
+
+        push(&mut self.entries, self.limit, entry);
+
+        if self.written >= self.limit.max(1) * 2 {
+            self.compact();
+        }
+    }
+
+    // rewrites the file with only the entries kept in memory
+    fn compact(&mut self) {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+
+        let mut text = String::new();
+
+        for entry in &self.entries {
+            text += &serde_json::to_string(entry).unwrap();
+            text += "\n";
+        }
+
+        let result = fs::write(&temp, text)
+            .and_then(|()| fs::rename(&temp, &self.path))
+            .and_then(|()| OpenOptions::new().append(true).open(&self.path));
+
+        match result {
+            Ok(compacted) => {
+                self.file = compacted;
+                self.written = self.entries.len();
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to compact history file");
+            }
+        }
+    }
+}
+
+fn push(entries: &mut VecDeque<Entry>, limit: usize, entry: Entry) {
+    if limit == 0 {
+        return;
+    }
+
+    if entries.len() == limit {
+        entries.pop_front();
+    }
+
+    entries.push_back(entry);
+}
+
+// oldest first, optionally only the most recent changes
+#[get("/history?<limit>")]
+pub async fn history(
+    _auth: Authorized<ReadOnly>,
+    limit: Option<usize>,
+    lights: &State<SharedLights>,
+) -> Json<Vec<Entry>> {
+    let lights = lights.lock().await;
+    let entries = lights.get_history().entries();
+
+    let skip = entries.len().saturating_sub(limit.unwrap_or(usize::MAX));
+
+    Json(entries.skip(skip).cloned().collect())
+}
@@ -0,0 +1,403 @@
+// types shared by the daemon and lightsctl
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rocket::form::{Error as FormError, FromFormField, Result as FormResult, ValueField};
+use rocket::serde::de::Error as DeError;
+use rocket::serde::{Deserialize, Deserializer, Serialize};
+
+use serde_with::{serde_as, DurationMilliSeconds};
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+#[derive(Debug)]
+enum ColorErrorKind {
+    BadFormat,
+    ParseError,
+}
+
+#[derive(Debug)]
+pub struct ColorError {
+    kind: ColorErrorKind,
+}
+
+impl Error for ColorError {}
+
+impl Display for ColorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match &self.kind {
+            ColorErrorKind::BadFormat => {
+                write!(f, "unknown color format")
+            }
+            ColorErrorKind::ParseError => {
+                write!(f, "error parsing color format")
+            }
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = ColorError;
+
+    fn from_str(color: &str) -> Result<Self, Self::Err> {
+        // checked before slicing, which would panic inside a multibyte character
+        if color.len() != 7 || !color.is_ascii() || !color.starts_with('#') {
+            return Err(Self::Err {
+                kind: ColorErrorKind::BadFormat,
+            });
+        }
+
+        let result = || -> Result<Color, ParseIntError> {
+            let red = u8::from_str_radix(&color[1..3], 16)?;
+            let green = u8::from_str_radix(&color[3..5], 16)?;
+            let blue = u8::from_str_radix(&color[5..7], 16)?;
+
+            Ok(Color { red, green, blue })
+        }();
+
+        match result {
+            Ok(color) => Ok(color),
+            Err(_err) => Err(Self::Err {
+                kind: ColorErrorKind::ParseError,
+            }),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for Color {
+    fn from_value(field: ValueField<'r>) -> FormResult<'r, Self> {
+        match Color::from_str(field.value) {
+            Ok(color) => Ok(color),
+            Err(err) => Err(FormError::custom(err).into()),
+        }
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl Color {
+    // for interfaces that treat brightness separately, split into a full brightness color and its brightness
+    pub fn split_brightness(self) -> (Color, u8) {
+        let brightness = self.red.max(self.green).max(self.blue);
+
+        if brightness == 0 {
+            return (self, 0);
+        }
+
+        let scale = |channel: u8| (channel as u16 * 255 / brightness as u16) as u8;
+
+        (
+            Color {
+                red: scale(self.red),
+                green: scale(self.green),
+                blue: scale(self.blue),
+            },
+            brightness,
+        )
+    }
+
+    pub fn with_brightness(self, brightness: u8) -> Color {
+        let scale = |channel: u8| (channel as u16 * brightness as u16 / 255) as u8;
+
+        Color {
+            red: scale(self.red),
+            green: scale(self.green),
+            blue: scale(self.blue),
+        }
+    }
+
+    // linear blend towards another color, from none at 0.0 to all of it at 1.0
+    pub fn mix(self, other: Color, amount: f64) -> Color {
+        let blend =
+            |from: u8, to: u8| (from as f64 + (to as f64 - from as f64) * amount).round() as u8;
+
+        Color {
+            red: blend(self.red, other.red),
+            green: blend(self.green, other.green),
+            blue: blend(self.blue, other.blue),
+        }
+    }
+}
+
+// tempo accepted from every interface, which also keeps frame durations in beats representable
+pub const BPM_MIN: f64 = 1.0;
+pub const BPM_MAX: f64 = 999.0;
+
+const BEATS_MAX: f64 = 10000.0;
+
+pub fn valid_bpm(bpm: f64) -> bool {
+    (BPM_MIN..=BPM_MAX).contains(&bpm)
+}
+
+fn beats<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let beats = f64::deserialize(deserializer)?;
+
+    if (0.0..=BEATS_MAX).contains(&beats) {
+        Ok(beats)
+    } else {
+        Err(D::Error::custom(format!(
+            "beats must be between 0 and {}",
+            BEATS_MAX
+        )))
+    }
+}
+
+#[serde_as]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Timing {
+    Duration(#[serde_as(as = "DurationMilliSeconds")] Duration),
+    // beats at the global tempo, so frames follow tempo changes
+    Beats(#[serde(deserialize_with = "beats")] f64),
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", try_from = "FrameFields")]
+pub struct Frame {
+    pub color: Color,
+    #[serde(flatten)]
+    pub timing: Timing,
+}
+
+fn some_beats<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    beats(deserializer).map(Some)
+}
+
+// a flattened timing quietly takes whichever of duration and beats comes first, so frames are
+// read field by field to reject both
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct FrameFields {
+    color: Color,
+    #[serde_as(as = "Option<DurationMilliSeconds>")]
+    duration: Option<Duration>,
+    #[serde(default, deserialize_with = "some_beats")]
+    beats: Option<f64>,
+}
+
+impl TryFrom<FrameFields> for Frame {
+    type Error = &'static str;
+
+    fn try_from(fields: FrameFields) -> Result<Frame, Self::Error> {
+        let timing = match (fields.duration, fields.beats) {
+            (Some(duration), None) => Timing::Duration(duration),
+            (None, Some(beats)) => Timing::Beats(beats),
+            (Some(_duration), Some(_beats)) => {
+                return Err("frame must have either duration or beats, not both");
+            }
+            (None, None) => {
+                return Err("frame must have either duration or beats");
+            }
+        };
+
+        Ok(Frame {
+            color: fields.color,
+            timing,
+        })
+    }
+}
+
+impl Frame {
+    pub fn duration(&self, bpm: f64) -> Duration {
+        match self.timing {
+            Timing::Duration(duration) => duration,
+            // too long to represent only happens with frames built in code, so hold them forever
+            Timing::Beats(beats) => {
+                Duration::try_from_secs_f64((beats * 60.0 / bpm).max(0.0)).unwrap_or(Duration::MAX)
+            }
+        }
+    }
+}
+
+fn audio_gain() -> f64 {
+    1.0
+}
+
+pub fn valid_gain(gain: f64) -> bool {
+    gain.is_finite() && gain >= 0.0
+}
+
+fn gain<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let gain = f64::deserialize(deserializer)?;
+
+    if valid_gain(gain) {
+        Ok(gain)
+    } else {
+        Err(D::Error::custom(
+            "gain must be a finite non-negative number",
+        ))
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Audio {
+    // multiplier on the audio level before it becomes brightness
+    #[serde(default = "audio_gain", deserialize_with = "gain")]
+    gain: f64,
+}
+
+impl Audio {
+    pub fn new(gain: f64) -> Audio {
+        Audio { gain }
+    }
+
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    crate = "rocket::serde",
+    rename_all = "lowercase",
+    tag = "type",
+    content = "content"
+)]
+pub enum Pattern {
+    Off,
+    Solid(Color),
+    Custom(Vec<Frame>),
+    Audio(Audio),
+}
+
+impl Pattern {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pattern::Off => "off",
+            Pattern::Solid(_color) => "solid",
+            Pattern::Custom(_frames) => "custom",
+            Pattern::Audio(_audio) => "audio",
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            Pattern::Off => Color {
+                red: 0,
+                green: 0,
+                blue: 0,
+            },
+            Pattern::Solid(color) => *color,
+            Pattern::Custom(frames) => match frames.first() {
+                Some(frame) => frame.color,
+                None => Color {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                },
+            },
+            // audio follows the music, so treat it as full brightness white
+            Pattern::Audio(_audio) => Color {
+                red: 255,
+                green: 255,
+                blue: 255,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rocket::serde::json::serde_json;
+
+    #[test]
+    fn color_from_str() {
+        let color: Color = "#ff8000".parse().unwrap();
+
+        assert_eq!((color.red, color.green, color.blue), (255, 128, 0));
+    }
+
+    #[test]
+    fn color_from_str_rejects_malformed() {
+        for color in [
+            "", "#", "ff8000", "#ff800", "#ff80000", "#gg8000", "#ff800é", "é#ff80",
+        ] {
+            assert!(color.parse::<Color>().is_err(), "{:?} parsed", color);
+        }
+    }
+
+    #[test]
+    fn audio_gain_rejects_invalid() {
+        let audio: Audio = serde_json::from_str("{}").unwrap();
+        assert_eq!(audio.gain(), 1.0);
+
+        let audio: Audio = serde_json::from_str(r#"{"gain": 0}"#).unwrap();
+        assert_eq!(audio.gain(), 0.0);
+
+        assert!(serde_json::from_str::<Audio>(r#"{"gain": -0.5}"#).is_err());
+
+        for gain in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(!valid_gain(gain));
+        }
+    }
+
+    fn frame(json: &str) -> Result<Frame, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn frame_timing() {
+        let color = r#""color": {"red": 1, "green": 2, "blue": 3}"#;
+
+        let duration = frame(&format!(r#"{{{}, "duration": 250}}"#, color)).unwrap();
+        assert!(duration.timing == Timing::Duration(Duration::from_millis(250)));
+
+        let beats = frame(&format!(r#"{{{}, "beats": 0.5}}"#, color)).unwrap();
+        assert!(beats.timing == Timing::Beats(0.5));
+
+        assert!(frame(&format!(r#"{{{}, "duration": 250, "beats": 0.5}}"#, color)).is_err());
+        assert!(frame(&format!(r#"{{{}, "beats": 0.5, "duration": 250}}"#, color)).is_err());
+        assert!(frame(&format!(r#"{{{}}}"#, color)).is_err());
+        assert!(frame(&format!(r#"{{{}, "beats": -1}}"#, color)).is_err());
+    }
+
+    #[test]
+    fn frame_duration_at_tempo_bounds() {
+        let frame = |timing| Frame {
+            color: Color {
+                red: 0,
+                green: 0,
+                blue: 0,
+            },
+            timing,
+        };
+
+        let beat = frame(Timing::Beats(1.0));
+        assert_eq!(beat.duration(BPM_MIN), Duration::from_secs(60));
+        assert_eq!(beat.duration(120.0), Duration::from_millis(500));
+        assert_eq!(
+            beat.duration(BPM_MAX),
+            Duration::from_secs_f64(60.0 / BPM_MAX)
+        );
+
+        // the longest frame at the slowest tempo is still representable
+        let longest = frame(Timing::Beats(BEATS_MAX));
+        assert_eq!(
+            longest.duration(BPM_MIN),
+            Duration::from_secs(60 * BEATS_MAX as u64)
+        );
+
+        let fixed = frame(Timing::Duration(Duration::from_millis(250)));
+        assert_eq!(fixed.duration(BPM_MIN), Duration::from_millis(250));
+        assert_eq!(fixed.duration(BPM_MAX), Duration::from_millis(250));
+    }
+}
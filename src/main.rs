@@ -1,209 +1,333 @@
 #[macro_use]
 extern crate rocket;
 
+mod audio;
+mod auth;
+mod dmx;
+mod history;
+mod metrics;
+#[cfg(feature = "alsa")]
+mod midi;
+mod mqtt;
+mod opc;
+mod osc;
+mod status;
+mod tls;
+mod wled;
+mod ws;
+
 use std::env;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::net::SocketAddr;
-use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
 use std::sync::Arc;
 
+use rocket::config::LogLevel;
 use rocket::fairing::AdHoc;
-use rocket::form::{Error as FormError, Form, FromFormField, Result as FormResult, ValueField};
+use rocket::form::Form;
 use rocket::fs::NamedFile;
+use rocket::http::uri::Origin;
 use rocket::http::Status;
 use rocket::response::Redirect;
 use rocket::{Config, State};
 
-use rocket::futures::sink::SinkExt;
-use rocket::futures::stream::{SplitSink, StreamExt};
-
-use rocket::serde::json::serde_json;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
 
-use rocket::tokio;
-use rocket::tokio::net::{TcpListener, TcpStream, UdpSocket};
-use rocket::tokio::sync::Mutex;
+use rocket::tokio::sync::{broadcast, Mutex};
 use rocket::tokio::time;
 use rocket::tokio::time::{Duration, Instant};
 
 use rocket_dyn_templates::Template;
 
-use rosc::{OscPacket, OscType};
-
 use rppal::gpio::{Gpio, OutputPin};
 
-use serde_with::{serde_as, DurationMilliSeconds};
+use tracing::{error, info, warn};
 
-use tokio_tungstenite::tungstenite::error::ProtocolError as WSProtocolError;
-use tokio_tungstenite::tungstenite::{Error as WSError, Message as WSMessage};
-use tokio_tungstenite::WebSocketStream;
+use tracing_subscriber::EnvFilter;
 
 use yansi::Paint;
 
-#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
-#[serde(crate = "rocket::serde")]
-struct Color {
-    red: u8,
-    green: u8,
-    blue: u8,
-}
+use fooster_lights::{Audio, Color, Frame, Pattern, Timing, BPM_MAX, BPM_MIN};
+
+use auth::{Admin, Authorized, ColorOnly, ReadOnly, Tokens};
+use history::{History, Source};
+use metrics::{FrameWindow, Metrics, SharedMetrics};
+use status::{SharedTasks, Tasks};
+
+const BPM_DEFAULT: f64 = 120.0;
+// taps further apart than this start a new tempo
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+const TAP_HISTORY: usize = 8;
+
+// patterns that can be undone, with older ones dropped
+const UNDO_HISTORY: usize = 32;
+// changes from the same source this close together are undone as one, like dragging a slider
+const UNDO_MERGE: Duration = Duration::from_secs(1);
+
+// shorter strobe periods are indistinguishable from a steady color and zero cannot be timed
+const STROBE_PERIOD_MIN: Duration = Duration::from_micros(1);
+
+// failed writes are retried after a delay that doubles up to a limit
+const OUTPUT_RETRY_MIN: Duration = Duration::from_millis(10);
+const OUTPUT_RETRY_MAX: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
-enum ColorErrorKind {
-    BadFormat,
-    ParseError,
+enum OutputErrorKind {
+    Pwm(rppal::gpio::Error),
+    Release(rppal::gpio::Error),
 }
 
 #[derive(Debug)]
-struct ColorError {
-    kind: ColorErrorKind,
+struct OutputError {
+    channel: &'static str,
+    kind: OutputErrorKind,
 }
 
-impl Error for ColorError {}
+impl Error for OutputError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            OutputErrorKind::Pwm(err) | OutputErrorKind::Release(err) => Some(err),
+        }
+    }
+}
 
-impl Display for ColorError {
+impl Display for OutputError {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match &self.kind {
-            ColorErrorKind::BadFormat => {
-                write!(f, "unknown color format")
+            OutputErrorKind::Pwm(err) => {
+                write!(f, "error setting {} pwm: {}", self.channel, err)
             }
-            ColorErrorKind::ParseError => {
-                write!(f, "error parsing color format")
+            OutputErrorKind::Release(err) => {
+                write!(f, "error releasing {} pin: {}", self.channel, err)
             }
         }
     }
 }
 
-impl FromStr for Color {
-    type Err = ColorError;
-
-    fn from_str(color: &str) -> Result<Self, Self::Err> {
-        if &color[0..1] != "#" || color.len() != 7 {
-            return Err(Self::Err {
-                kind: ColorErrorKind::BadFormat,
-            });
-        }
+struct OutputFailure {
+    error: OutputError,
+    at: Instant,
+    // consecutive failures, reset once a write succeeds again
+    failures: u32,
+    retry: Instant,
+}
 
-        let result = || -> Result<Color, ParseIntError> {
-            let red = u8::from_str_radix(&color[1..3], 16)?;
-            let green = u8::from_str_radix(&color[3..5], 16)?;
-            let blue = u8::from_str_radix(&color[5..7], 16)?;
+enum Output {
+    Gpio {
+        frequency: f64,
 
-            Ok(Color { red, green, blue })
-        }();
+        red: OutputPin,
+        green: OutputPin,
+        blue: OutputPin,
+    },
+    // prints colors instead of driving pins, for running without a Raspberry Pi
+    Simulated,
+}
 
-        match result {
-            Ok(color) => Ok(color),
-            Err(_err) => Err(Self::Err {
-                kind: ColorErrorKind::ParseError,
-            }),
+impl Output {
+    fn name(&self) -> &'static str {
+        match self {
+            Output::Gpio { .. } => "gpio",
+            Output::Simulated => "simulated",
         }
     }
-}
 
-#[rocket::async_trait]
-impl<'r> FromFormField<'r> for Color {
-    fn from_value(field: ValueField<'r>) -> FormResult<'r, Self> {
-        match Color::from_str(field.value) {
-            Ok(color) => Ok(color),
-            Err(err) => Err(FormError::custom(err).into()),
+    fn set(&mut self, color: Color) -> Result<(), OutputError> {
+        match self {
+            Output::Gpio {
+                frequency,
+                red,
+                green,
+                blue,
+            } => {
+                for (channel, pin, value) in [
+                    ("red", red, color.red),
+                    ("green", green, color.green),
+                    ("blue", blue, color.blue),
+                ] {
+                    pin.set_pwm_frequency(*frequency, value as f64 / 255.0)
+                        .map_err(|err| OutputError {
+                            channel,
+                            kind: OutputErrorKind::Pwm(err),
+                        })?;
+                }
+            }
+            Output::Simulated => {
+                println!(
+                    "{} {}",
+                    Paint::rgb(color.red, color.green, color.blue, "██"),
+                    color
+                );
+            }
         }
-    }
-}
 
-impl Display for Color {
-    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+        Ok(())
     }
-}
 
-#[serde_as]
-#[derive(Clone, Serialize, Deserialize)]
-#[serde(crate = "rocket::serde")]
-struct Frame {
-    color: Color,
-    #[serde_as(as = "DurationMilliSeconds")]
-    duration: Duration,
-}
+    // software pwm stops with the process, so pins are left driven fully on or off instead of
+    // floating, which is as close to the color as they can hold
+    fn release(&mut self, color: Color) -> Result<(), OutputError> {
+        match self {
+            Output::Gpio {
+                red, green, blue, ..
+            } => {
+                for (channel, pin, value) in [
+                    ("red", red, color.red),
+                    ("green", green, color.green),
+                    ("blue", blue, color.blue),
+                ] {
+                    pin.clear_pwm().map_err(|err| OutputError {
+                        channel,
+                        kind: OutputErrorKind::Release(err),
+                    })?;
+                    pin.set_reset_on_drop(false);
+
+                    if value >= 128 {
+                        pin.set_high();
+                    } else {
+                        pin.set_low();
+                    }
+                }
+            }
+            Output::Simulated => {}
+        }
 
-#[derive(Clone, Serialize, Deserialize)]
-#[serde(
-    crate = "rocket::serde",
-    rename_all = "lowercase",
-    tag = "type",
-    content = "content"
-)]
-enum Pattern {
-    Off,
-    Solid(Color),
-    Custom(Vec<Frame>),
+        Ok(())
+    }
 }
 
-struct Output {
-    frequency: f64,
-
-    red: OutputPin,
-    green: OutputPin,
-    blue: OutputPin,
+#[derive(Clone)]
+enum Change {
+    // pattern was set through one of the interfaces
+    Pattern { pattern: Pattern, color: Color },
+    // running pattern advanced to a new color
+    Frame { color: Color },
 }
 
-impl Output {
-    fn set(&mut self, color: Color) -> rppal::gpio::Result<()> {
-        self.red
-            .set_pwm_frequency(self.frequency, color.red as f64 / 255.0)?;
-        self.green
-            .set_pwm_frequency(self.frequency, color.green as f64 / 255.0)?;
-        self.blue
-            .set_pwm_frequency(self.frequency, color.blue as f64 / 255.0)?;
+struct Realtime {
+    source: Source,
+    color: Color,
+    strobe: Option<Duration>,
+    since: Instant,
+    until: Option<Instant>,
+}
 
-        Ok(())
+impl Realtime {
+    fn get(&self) -> Color {
+        match self.strobe {
+            // lit for the first half of every strobe period
+            Some(period)
+                if self.since.elapsed().as_nanos() % period.as_nanos() >= period.as_nanos() / 2 =>
+            {
+                Color {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                }
+            }
+            _ => self.color,
+        }
     }
 }
 
 struct Lights {
     output: Output,
+    output_failure: Option<OutputFailure>,
     pattern: Pattern,
+    on: Pattern,
 
     frame: usize,
     instant: Instant,
 
+    realtime: Option<Realtime>,
+
+    audio: audio::AudioLevels,
+
+    bpm: f64,
+    taps: VecDeque<Instant>,
+
     last: Color,
+
+    changes: broadcast::Sender<Change>,
+    announced: Color,
+
+    history: History,
+
+    undo: VecDeque<Pattern>,
+    redo: Vec<Pattern>,
+    // the last change that can be merged into, cleared by undoing and redoing
+    undo_last: Option<(Source, Instant)>,
 }
 
 impl Lights {
-    fn new(output: Output, pattern: Pattern) -> Lights {
+    fn new(output: Output, pattern: Pattern, history: History) -> Lights {
+        let (changes, _) = broadcast::channel(64);
+
+        let on = match pattern {
+            Pattern::Off => Pattern::Solid(Color {
+                red: 255,
+                green: 255,
+                blue: 255,
+            }),
+            _ => pattern.clone(),
+        };
+
         let mut lights = Lights {
             output,
+            output_failure: None,
             pattern,
+            on,
 
             frame: 0,
             instant: Instant::now(),
+
+            realtime: None,
+
+            audio: audio::AudioLevels::default(),
+
+            bpm: BPM_DEFAULT,
+            taps: VecDeque::with_capacity(TAP_HISTORY),
+
             last: Color {
                 red: 0,
                 green: 0,
                 blue: 0,
             },
-        };
 
-        lights
-            .output
-            .set(Color {
+            changes,
+            announced: Color {
                 red: 0,
                 green: 0,
                 blue: 0,
-            })
-            .expect("Lights output failure");
+            },
+
+            history,
+
+            undo: VecDeque::with_capacity(UNDO_HISTORY),
+            redo: Vec::new(),
+            undo_last: None,
+        };
+
+        lights.announced = lights.get();
+
+        lights.set_output(Color {
+            red: 0,
+            green: 0,
+            blue: 0,
+        });
 
         lights
     }
 
     fn get(&self) -> Color {
+        if let Some(realtime) = &self.realtime {
+            return realtime.get();
+        }
+
         match &self.pattern {
             Pattern::Off => Color {
                 red: 0,
@@ -222,23 +346,287 @@ impl Lights {
                     frames[self.frame].color
                 }
             }
+            Pattern::Audio(audio) => self.audio.color(audio),
+        }
+    }
+
+    fn set(&mut self, color: Color, source: Source) {
+        self.set_pattern(&Pattern::Solid(color), source);
+    }
+
+    // color last written to the output, which lags behind the pattern by up to a tick
+    fn get_output(&self) -> Color {
+        self.last
+    }
+
+    // writes straight to the output, bypassing the pattern
+    fn set_output(&mut self, color: Color) {
+        // the pattern keeps running while failed writes wait to be retried
+        if let Some(failure) = &self.output_failure {
+            if failure.failures > 0 && Instant::now() < failure.retry {
+                return;
+            }
+        }
+
+        match self.output.set(color) {
+            Ok(()) => {
+                self.last = color;
+
+                if let Some(failure) = &mut self.output_failure {
+                    if failure.failures > 0 {
+                        info!(failures = failure.failures, "Lights output recovered");
+
+                        failure.failures = 0;
+                    }
+                }
+            }
+            Err(error) => {
+                let failures = match &self.output_failure {
+                    Some(failure) => failure.failures.saturating_add(1),
+                    None => 1,
+                };
+
+                let delay = OUTPUT_RETRY_MIN
+                    .saturating_mul(2u32.saturating_pow(failures - 1))
+                    .min(OUTPUT_RETRY_MAX);
+
+                error!(
+                    %error,
+                    retry_ms = delay.as_millis() as u64,
+                    "Lights output failure"
+                );
+
+                self.output_failure = Some(OutputFailure {
+                    error,
+                    at: Instant::now(),
+                    failures,
+                    retry: Instant::now() + delay,
+                });
+            }
+        }
+    }
+
+    // leaves the output holding a color after the process exits
+    fn release(&mut self, color: Color) {
+        if let Err(err) = self.output.release(color) {
+            error!(error = %err, "Failed to release lights output");
         }
     }
 
-    fn set(&mut self, color: Color) {
-        self.pattern = Pattern::Solid(color);
+    fn get_output_backend(&self) -> &'static str {
+        self.output.name()
+    }
+
+    fn get_output_failure(&self) -> Option<&OutputFailure> {
+        self.output_failure.as_ref()
     }
 
     fn get_pattern(&self) -> &Pattern {
         &self.pattern
     }
 
-    fn set_pattern(&mut self, pattern: &Pattern) {
+    fn set_pattern(&mut self, pattern: &Pattern, source: Source) {
+        // setting the same pattern again leaves nothing to undo
+        if *pattern != self.pattern {
+            let merged = self.undo_last.is_some_and(|(last, instant)| {
+                last.same_origin(&source) && instant.elapsed() < UNDO_MERGE
+            });
+
+            if !merged {
+                if self.undo.len() == UNDO_HISTORY {
+                    self.undo.pop_front();
+                }
+
+                self.undo.push_back(self.pattern.clone());
+            }
+
+            self.redo.clear();
+            self.undo_last = Some((source, Instant::now()));
+        }
+
+        self.apply_pattern(pattern, source);
+    }
+
+    // restores the pattern before the last change, returning false if there is none
+    fn undo(&mut self, source: Source) -> bool {
+        match self.undo.pop_back() {
+            Some(pattern) => {
+                self.undo_last = None;
+                self.redo.push(self.pattern.clone());
+                self.apply_pattern(&pattern, source);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    // restores the pattern last undone, until another change is made
+    fn redo(&mut self, source: Source) -> bool {
+        match self.redo.pop() {
+            Some(pattern) => {
+                self.undo_last = None;
+                self.undo.push_back(self.pattern.clone());
+                self.apply_pattern(&pattern, source);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn apply_pattern(&mut self, pattern: &Pattern, source: Source) {
+        // setting the same pattern still restarts it, but is not a change worth recording
+        if *pattern != self.pattern {
+            self.history.record(source, &self.pattern, pattern);
+        }
+
         self.pattern = pattern.clone();
+
+        if !matches!(self.pattern, Pattern::Off) {
+            self.on = self.pattern.clone();
+        }
+
+        self.frame = 0;
+        self.instant = Instant::now();
+
+        self.announced = self.get();
+
+        // sending only fails when nobody is subscribed
+        let _ = self.changes.send(Change::Pattern {
+            pattern: self.pattern.clone(),
+            color: self.announced,
+        });
+    }
+
+    fn get_history(&self) -> &History {
+        &self.history
+    }
+
+    // last pattern that was not off, for interfaces that can turn the lights back on
+    fn get_on_pattern(&self) -> &Pattern {
+        &self.on
+    }
+
+    fn get_realtime(&self) -> bool {
+        self.realtime.is_some()
+    }
+
+    // realtime colors take precedence over the pattern until they time out or are cleared
+    fn set_realtime(
+        &mut self,
+        color: Color,
+        strobe: Option<Duration>,
+        timeout: Option<Duration>,
+        source: Source,
+    ) {
+        let strobe = match strobe {
+            Some(period) if period < STROBE_PERIOD_MIN => {
+                warn!(period = ?period, "Ignoring strobe period that is too short");
+                None
+            }
+            strobe => strobe,
+        };
+
+        // keep the strobe phase when the same strobe is sent again with every packet
+        let since = match &self.realtime {
+            Some(realtime) if realtime.strobe == strobe => realtime.since,
+            _ => Instant::now(),
+        };
+
+        // only the takeover is recorded, not every packet that follows
+        if self.realtime.is_none() {
+            self.history.record_realtime(source, true);
+        }
+
+        self.realtime = Some(Realtime {
+            source,
+            color,
+            strobe,
+            since,
+            until: timeout.map(|timeout| Instant::now() + timeout),
+        });
+    }
+
+    fn clear_realtime(&mut self, source: Source) {
+        if self.realtime.take().is_some() {
+            self.history.record_realtime(source, false);
+        }
+    }
+
+    // clears realtime only while the source still holds it
+    fn release_realtime(&mut self, source: Source) {
+        if self
+            .realtime
+            .as_ref()
+            .is_some_and(|realtime| realtime.source == source)
+        {
+            self.clear_realtime(source);
+        }
+    }
+
+    fn set_audio(&mut self, levels: audio::AudioLevels) {
+        self.audio = levels;
+    }
+
+    fn get_bpm(&self) -> f64 {
+        self.bpm
+    }
+
+    // running patterns are re-timed on the next tick since beat durations are computed from the tempo
+    fn set_bpm(&mut self, bpm: f64) {
+        self.bpm = bpm;
+    }
+
+    fn tap(&mut self) -> f64 {
+        let now = Instant::now();
+
+        if let Some(last) = self.taps.back() {
+            if now.duration_since(*last) > TAP_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+
+        if self.taps.len() == TAP_HISTORY {
+            self.taps.pop_front();
+        }
+
+        self.taps.push_back(now);
+
+        // the tempo is the average interval over the recent taps
+        if let (Some(first), Some(last)) = (self.taps.front(), self.taps.back()) {
+            if self.taps.len() >= 2 {
+                let interval =
+                    last.duration_since(*first).as_secs_f64() / (self.taps.len() - 1) as f64;
+
+                self.bpm = (60.0 / interval).clamp(BPM_MIN, BPM_MAX);
+            }
+        }
+
+        self.bpm
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
     }
 
     fn tick(&mut self) {
+        if let Some(Realtime {
+            source,
+            until: Some(until),
+            ..
+        }) = &self.realtime
+        {
+            // timing out releases on behalf of whoever took over
+            if *until <= Instant::now() {
+                let source = *source;
+
+                self.clear_realtime(source);
+            }
+        }
+
         let next = match &self.pattern {
+            _ if self.realtime.is_some() => self.get(),
             Pattern::Off => Color {
                 red: 0,
                 green: 0,
@@ -260,22 +648,38 @@ impl Lights {
                         self.frame = 0;
                     }
 
-                    while self.instant.elapsed() >= frames[self.frame].duration {
-                        self.instant = self
-                            .instant
-                            .checked_add(frames[self.frame].duration)
-                            .unwrap();
-                        self.frame = (self.frame + 1) % frames.len();
+                    // a pattern without any duration would never finish advancing
+                    if frames
+                        .iter()
+                        .all(|frame| frame.duration(self.bpm).is_zero())
+                    {
+                        self.instant = Instant::now();
+                        self.frame = 0;
+                    } else {
+                        while self.instant.elapsed() >= frames[self.frame].duration(self.bpm) {
+                            self.instant = self
+                                .instant
+                                .checked_add(frames[self.frame].duration(self.bpm))
+                                .unwrap();
+                            self.frame = (self.frame + 1) % frames.len();
+                        }
                     }
 
                     frames[self.frame].color
                 }
             }
+            Pattern::Audio(audio) => self.audio.color(audio),
         };
 
         if next != self.last {
-            self.output.set(next).expect("Lights output failure");
-            self.last = next;
+            self.set_output(next);
+        }
+
+        if next != self.announced {
+            self.announced = next;
+
+            // sending only fails when nobody is subscribed
+            let _ = self.changes.send(Change::Frame { color: next });
         }
     }
 }
@@ -295,34 +699,81 @@ struct ColorForm {
 type SharedLights = Arc<Mutex<Lights>>;
 
 #[get("/color")]
-async fn get_color(lights: &State<SharedLights>) -> Json<Color> {
+async fn get_color(_auth: Authorized<ReadOnly>, lights: &State<SharedLights>) -> Json<Color> {
     Json(lights.lock().await.get())
 }
 
 #[put("/color", data = "<color>")]
-async fn set_color(color: Json<Color>, lights: &State<SharedLights>) -> Status {
-    lights.lock().await.set(*color);
+async fn set_color(
+    _auth: Authorized<ColorOnly>,
+    peer: SocketAddr,
+    color: Json<Color>,
+    lights: &State<SharedLights>,
+) -> Status {
+    let source = Source::Http(peer);
+
+    info!(%source, color = %*color, "Set color");
+    lights.lock().await.set(*color, source);
 
     Status::NoContent
 }
 
 #[get("/pattern")]
-async fn get_pattern(lights: &State<SharedLights>) -> Json<Pattern> {
+async fn get_pattern(_auth: Authorized<ReadOnly>, lights: &State<SharedLights>) -> Json<Pattern> {
     Json(lights.lock().await.get_pattern().clone())
 }
 
 #[put("/pattern", data = "<pattern>")]
-async fn set_pattern(pattern: Json<Pattern>, lights: &State<SharedLights>) -> Status {
-    lights.lock().await.set_pattern(&pattern);
+async fn set_pattern(
+    _auth: Authorized<Admin>,
+    peer: SocketAddr,
+    pattern: Json<Pattern>,
+    lights: &State<SharedLights>,
+) -> Status {
+    let source = Source::Http(peer);
+
+    info!(%source, pattern = pattern.name(), "Set pattern");
+    lights.lock().await.set_pattern(&pattern, source);
 
     Status::NoContent
 }
 
-#[get("/wsinfo")]
-async fn ws_info() -> String {
-    match env::var("WS_INFO") {
-        Ok(val) => val,
-        Err(_err) => String::from(""),
+#[get("/bpm")]
+async fn get_bpm(_auth: Authorized<ReadOnly>, lights: &State<SharedLights>) -> Json<f64> {
+    Json(lights.lock().await.get_bpm())
+}
+
+#[put("/bpm", data = "<bpm>")]
+async fn set_bpm(_auth: Authorized<Admin>, bpm: Json<f64>, lights: &State<SharedLights>) -> Status {
+    if !fooster_lights::valid_bpm(*bpm) {
+        return Status::UnprocessableEntity;
+    }
+
+    lights.lock().await.set_bpm(*bpm);
+
+    Status::NoContent
+}
+
+#[post("/tap")]
+async fn tap(_auth: Authorized<Admin>, lights: &State<SharedLights>) -> Json<f64> {
+    Json(lights.lock().await.tap())
+}
+
+#[post("/undo")]
+async fn undo(_auth: Authorized<Admin>, peer: SocketAddr, lights: &State<SharedLights>) -> Status {
+    if lights.lock().await.undo(Source::Http(peer)) {
+        Status::NoContent
+    } else {
+        Status::Conflict
+    }
+}
+
+#[post("/redo")]
+async fn redo(_auth: Authorized<Admin>, peer: SocketAddr, lights: &State<SharedLights>) -> Status {
+    if lights.lock().await.redo(Source::Http(peer)) {
+        Status::NoContent
+    } else {
+        Status::Conflict
     }
 }
 
@@ -346,7 +797,7 @@ async fn manifest() -> Option<NamedFile> {
 }
 
 #[get("/")]
-async fn form(lights: &State<SharedLights>) -> Template {
+async fn form(_auth: Authorized<ReadOnly>, lights: &State<SharedLights>) -> Template {
     let context = [(String::from("color"), lights.lock().await.get().to_string())];
 
     Template::render(
@@ -356,10 +807,20 @@ async fn form(lights: &State<SharedLights>) -> Template {
 }
 
 #[post("/", data = "<color_form>")]
-async fn form_submit(color_form: Form<ColorForm>, lights: &State<SharedLights>) -> Redirect {
-    lights.lock().await.set(color_form.color);
-
-    Redirect::to(uri!(form))
+async fn form_submit(
+    origin: &Origin<'_>,
+    _auth: Authorized<ColorOnly>,
+    peer: SocketAddr,
+    color_form: Form<ColorForm>,
+    lights: &State<SharedLights>,
+) -> Redirect {
+    let source = Source::Form(peer);
+
+    info!(%source, color = %color_form.color, "Set color");
+    lights.lock().await.set(color_form.color, source);
+
+    // redirecting to the same uri keeps a token in the query string
+    Redirect::to(origin.to_string())
 }
 
 #[catch(400)]
@@ -378,6 +839,22 @@ async fn unprocessable_entity() -> Json<APIError> {
     })
 }
 
+#[catch(401)]
+async fn unauthorized() -> Json<APIError> {
+    Json(APIError {
+        status: String::from("error"),
+        message: String::from("Missing or invalid token"),
+    })
+}
+
+#[catch(403)]
+async fn forbidden() -> Json<APIError> {
+    Json(APIError {
+        status: String::from("error"),
+        message: String::from("Token not allowed to do that"),
+    })
+}
+
 #[catch(404)]
 async fn not_found() -> Json<APIError> {
     Json(APIError {
@@ -386,293 +863,107 @@ async fn not_found() -> Json<APIError> {
     })
 }
 
-async fn ws_server(lights: SharedLights, chronon: Duration) {
-    let address = match env::var("WS_ADDRESS") {
-        Ok(val) => val,
-        Err(_err) => String::from(if cfg!(debug_assertions) {
-            "127.0.0.1"
-        } else {
-            "0.0.0.0"
-        }),
-    };
-
-    let port: u16 = match env::var("WS_PORT") {
-        Ok(val) => val.parse().unwrap(),
-        Err(_err) => 8001,
-    };
-
-    let listener = TcpListener::bind((address, port))
-        .await
-        .expect("Failed to bind TCP WebSocket address");
-
+async fn pattern_output(lights: SharedLights, metrics: SharedMetrics, chronon: Duration) {
     println!(
-        "{}{} {}",
-        Paint::masked("🕸  "),
-        Paint::default("WebSocket server started on").bold(),
-        Paint::default(String::from("ws://") + &listener.local_addr().unwrap().to_string())
-            .bold()
-            .underline()
+        "{}{}",
+        Paint::masked("💡 "),
+        Paint::default("Light pattern output started").bold()
     );
 
-    let streams = Arc::new(Mutex::new(HashMap::<
-        SocketAddr,
-        SplitSink<WebSocketStream<TcpStream>, WSMessage>,
-    >::new()));
-
-    let mut last_color = lights.lock().await.get();
-
     let mut interval = time::interval(chronon);
 
+    let mut window = Instant::now();
+    let mut frames: u32 = 0;
+    let mut jitter_total = Duration::ZERO;
+    let mut jitter_max = Duration::ZERO;
+
     loop {
-        tokio::select! {
-            connection = listener.accept() => {
-                match connection {
-                    Ok((socket, _)) => {
-                        match tokio_tungstenite::accept_async(socket).await {
-                            Ok(stream) => {
-                                let peer = stream.get_ref().peer_addr().unwrap();
-
-                                let (mut sender, mut receiver) = stream.split();
-
-                                match sender.send(WSMessage::Text(serde_json::to_string(&lights.lock().await.get()).unwrap())).await {
-                                    Ok(_) => {},
-                                    Err(err) => {
-                                        // task should handle removal on I/O errors
-                                        eprintln!("Failed to send color to WebSocket: {}", err);
-                                    }
-                                }
-
-                                streams.lock().await.insert(peer, sender);
-
-                                let lights_conn = Arc::clone(&lights);
-                                let streams_conn = Arc::clone(&streams);
-
-                                tokio::spawn(async move {
-                                    loop {
-                                        match receiver.next().await {
-                                            Some(Ok(WSMessage::Text(string))) => {
-                                                match serde_json::from_str::<Color>(&string) {
-                                                    Ok(color) => {
-                                                        lights_conn.lock().await.set(color);
-                                                    },
-                                                    Err(err) => {
-                                                        eprintln!("Failed to parse color from WebSocket: {}", err);
-                                                    }
-                                                }
-                                            },
-                                            Some(Ok(WSMessage::Close(_frame))) => {
-                                                break;
-                                            },
-                                            Some(Ok(_)) => {
-                                                // ignore other message types
-                                            },
-                                            Some(Err(WSError::Protocol(WSProtocolError::ResetWithoutClosingHandshake))) => {
-                                                // resets seem to be common for browsers
-                                                break;
-                                            },
-                                            Some(Err(err)) => {
-                                                eprintln!("Failed to poll WebSocket connection: {}", err);
-                                                break;
-                                            },
-                                            None => {
-                                                break;
-                                            }
-                                        }
-                                    }
-
-                                    let removed = streams_conn.lock().await.remove(&peer);
-
-                                    if let Some(mut stream) = removed {
-                                        match stream.close().await {
-                                            Ok(()) => {},
-                                            Err(err) => {
-                                                eprintln!("Failed to close WebSocket connection: {}", err);
-                                            }
-                                        }
-                                    }
-                                });
-                            },
-                            Err(err) => {
-                                eprintln!("Failed to accept WebSocket connection: {}", err);
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        eprintln!("Failed to accept WebSocket connection: {}", err);
-                    }
-                }
-            }
+        let scheduled = interval.tick().await;
+        let jitter = scheduled.elapsed();
 
-            _ = interval.tick() => {
-                let color = lights.lock().await.get();
+        lights.lock().await.tick();
 
-                if color != last_color {
-                    let string = serde_json::to_string(&color).unwrap();
+        // the frame overran when it finished after the next one was due
+        metrics.frame(scheduled.elapsed() > chronon);
 
-                    for (_, stream) in streams.lock().await.iter_mut() {
-                        match stream.send(WSMessage::Text(string.clone())).await {
-                            Ok(_) => {},
-                            Err(err) => {
-                                // task should handle removal on I/O errors
-                                eprintln!("Failed to send color to WebSocket: {}", err);
-                            }
-                        }
-                    }
+        frames += 1;
+        jitter_total += jitter;
+        jitter_max = jitter_max.max(jitter);
 
-                    last_color = color;
-                }
-            }
+        if window.elapsed() >= Duration::from_secs(1) {
+            metrics.set_window(FrameWindow {
+                fps: frames as f64 / window.elapsed().as_secs_f64(),
+                jitter_mean: jitter_total / frames,
+                jitter_max,
+                updated: Instant::now(),
+            });
+
+            window = Instant::now();
+            frames = 0;
+            jitter_total = Duration::ZERO;
+            jitter_max = Duration::ZERO;
         }
     }
 }
 
-async fn osc_server(lights: SharedLights) {
-    let address = match env::var("OSC_ADDRESS") {
-        Ok(val) => val,
-        Err(_err) => String::from(if cfg!(debug_assertions) {
-            "127.0.0.1"
-        } else {
-            "0.0.0.0"
-        }),
-    };
+async fn shutdown(lights: SharedLights, tasks: SharedTasks, color: Color, fade: Duration) {
+    // nothing else may touch the output while fading
+    tasks.stop();
 
-    let port: u16 = match env::var("OSC_PORT") {
-        Ok(val) => val.parse().unwrap(),
-        Err(_err) => 1337,
-    };
+    let from = lights.lock().await.get_output();
 
-    let socket = UdpSocket::bind((address, port))
-        .await
-        .expect("Failed to bind UDP OSC address");
+    let start = Instant::now();
+    let mut interval = time::interval(Duration::from_millis(10));
 
-    println!(
-        "{}{} {}",
-        Paint::masked("🎛  "),
-        Paint::default("OSC server started on").bold(),
-        Paint::default(socket.local_addr().unwrap())
-            .bold()
-            .underline()
-    );
+    while start.elapsed() < fade {
+        interval.tick().await;
 
-    let mut buffer = [0u8; rosc::decoder::MTU];
+        let amount = start.elapsed().as_secs_f64() / fade.as_secs_f64();
 
-    loop {
-        match socket.recv_from(&mut buffer).await {
-            Ok((size, _addr)) => match rosc::decoder::decode_udp(&buffer[..size]) {
-                Ok(packet) => match packet {
-                    (_, OscPacket::Message(msg)) => match msg.addr.as_ref() {
-                        "/color" => match &msg.args[..] {
-                            [OscType::Int(red), OscType::Int(green), OscType::Int(blue)] => {
-                                lights.lock().await.set(Color {
-                                    red: *red as u8,
-                                    green: *green as u8,
-                                    blue: *blue as u8,
-                                });
-                            }
-                            [OscType::Float(red), OscType::Float(green), OscType::Float(blue)] => {
-                                lights.lock().await.set(Color {
-                                    red: *red as u8,
-                                    green: *green as u8,
-                                    blue: *blue as u8,
-                                });
-                            }
-                            [OscType::Double(red), OscType::Double(green), OscType::Double(blue)] =>
-                            {
-                                lights.lock().await.set(Color {
-                                    red: *red as u8,
-                                    green: *green as u8,
-                                    blue: *blue as u8,
-                                });
-                            }
-                            [OscType::Color(color)] => {
-                                lights.lock().await.set(Color {
-                                    red: color.red,
-                                    green: color.green,
-                                    blue: color.blue,
-                                });
-                            }
-                            _ => {
-                                eprintln!("Unexpected OSC /color command: {:?}", msg.args);
-                            }
-                        },
-                        "/pattern/off" => match &msg.args[..] {
-                            [] => {
-                                lights.lock().await.set_pattern(&Pattern::Off);
-                            }
-                            _ => {
-                                eprintln!("Unexpected OSC /pattern/off command: {:?}", msg.args);
-                            }
-                        },
-                        "/pattern/solid" => match &msg.args[..] {
-                            [OscType::Int(red), OscType::Int(green), OscType::Int(blue)] => {
-                                lights.lock().await.set_pattern(&Pattern::Solid(Color {
-                                    red: *red as u8,
-                                    green: *green as u8,
-                                    blue: *blue as u8,
-                                }));
-                            }
-                            [OscType::Float(red), OscType::Float(green), OscType::Float(blue)] => {
-                                lights.lock().await.set_pattern(&Pattern::Solid(Color {
-                                    red: *red as u8,
-                                    green: *green as u8,
-                                    blue: *blue as u8,
-                                }));
-                            }
-                            [OscType::Double(red), OscType::Double(green), OscType::Double(blue)] =>
-                            {
-                                lights.lock().await.set_pattern(&Pattern::Solid(Color {
-                                    red: *red as u8,
-                                    green: *green as u8,
-                                    blue: *blue as u8,
-                                }));
-                            }
-                            [OscType::Color(color)] => {
-                                lights.lock().await.set_pattern(&Pattern::Solid(Color {
-                                    red: color.red,
-                                    green: color.green,
-                                    blue: color.blue,
-                                }));
-                            }
-                            _ => {
-                                eprintln!("Unexpected OSC /pattern/solid command: {:?}", msg.args);
-                            }
-                        },
-                        _ => {
-                            eprintln!("Unexpected OSC Message: {}: {:?}", msg.addr, msg.args);
-                        }
-                    },
-                    (_, OscPacket::Bundle(bundle)) => {
-                        eprintln!("Unexpected OSC Bundle: {:?}", bundle);
-                    }
-                },
-                Err(err) => {
-                    eprintln!("Error decoding OSC packet: {:?}", err);
-                }
-            },
-            Err(err) => {
-                eprintln!("Error receiving from socket: {}", err);
-            }
-        }
+        lights
+            .lock()
+            .await
+            .set_output(from.mix(color, amount.min(1.0)));
     }
-}
 
-async fn pattern_output(lights: SharedLights, chronon: Duration) {
+    let mut lights = lights.lock().await;
+
+    if lights.get_output() != color {
+        lights.set_output(color);
+    }
+
+    lights.release(color);
+
     println!(
-        "{}{}",
+        "{}{} {}",
         Paint::masked("💡 "),
-        Paint::default("Light pattern output started").bold()
+        Paint::default("Light output released at").bold(),
+        Paint::default(color).bold().underline()
     );
+}
 
-    let mut interval = time::interval(chronon);
+fn logging() {
+    // levels use the env filter syntax, e.g. "info" or "warn,fooster_lights::mqtt=debug"
+    let filter = match env::var("LOG_LEVEL") {
+        Ok(val) => EnvFilter::try_new(&val).expect("Invalid log level"),
+        Err(_err) => EnvFilter::new("info"),
+    };
 
-    loop {
-        interval.tick().await;
-        lights.lock().await.tick();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().init(),
+        Ok("text") | Err(_) => subscriber.init(),
+        Ok(format) => panic!("Unknown log format: {}", format),
     }
 }
 
 #[launch]
 fn rocket() -> _ {
+    logging();
+
     let initial = Color {
         red: 242,
         green: 155,
@@ -681,32 +972,96 @@ fn rocket() -> _ {
 
     let chronon = Duration::from_millis(10);
 
-    let gpio = Gpio::new().unwrap();
+    let output = match env::var("OUTPUT").as_deref() {
+        Ok("simulated") => Output::Simulated,
+        Ok("gpio") | Err(_) => {
+            let gpio = Gpio::new().unwrap();
 
-    let lights = Arc::new(Mutex::new(Lights::new(
-        Output {
-            frequency: 60.0,
+            Output::Gpio {
+                frequency: 60.0,
 
-            red: gpio.get(17).unwrap().into_output(),
-            green: gpio.get(27).unwrap().into_output(),
-            blue: gpio.get(22).unwrap().into_output(),
-        },
+                red: gpio.get(17).unwrap().into_output(),
+                green: gpio.get(27).unwrap().into_output(),
+                blue: gpio.get(22).unwrap().into_output(),
+            }
+        }
+        Ok(output) => panic!("Unknown output: {}", output),
+    };
+
+    let lights = Arc::new(Mutex::new(Lights::new(
+        output,
         Pattern::Solid(initial),
+        History::from_env(),
     )));
 
     let lights_rocket = Arc::clone(&lights);
+    let tokens = Tokens::from_env();
+    let tokens_ws = tokens.clone();
+
     let lights_ws = Arc::clone(&lights);
     let lights_osc = Arc::clone(&lights);
+    let lights_mqtt = Arc::clone(&lights);
+    let lights_wled = Arc::clone(&lights);
+    let lights_e131 = Arc::clone(&lights);
+    let lights_artnet = Arc::clone(&lights);
+    let lights_opc = Arc::clone(&lights);
+    let lights_audio = Arc::clone(&lights);
+    #[cfg(feature = "alsa")]
+    let lights_midi = Arc::clone(&lights);
     let lights_output = Arc::clone(&lights);
 
-    rocket::custom(Config::figment().merge((
-        "address",
-        (if cfg!(debug_assertions) {
-            "127.0.0.1"
-        } else {
-            "0.0.0.0"
-        }),
-    )))
+    let metrics: SharedMetrics = Arc::new(Metrics::default());
+
+    let metrics_rocket = Arc::clone(&metrics);
+    let metrics_http = Arc::clone(&metrics);
+    let metrics_ws = Arc::clone(&metrics);
+    let metrics_osc = Arc::clone(&metrics);
+    let metrics_output = Arc::clone(&metrics);
+
+    let tasks: SharedTasks = Arc::new(Tasks::new());
+
+    let shutdown_color = match env::var("SHUTDOWN_COLOR") {
+        Ok(val) => val.parse().expect("Invalid shutdown color"),
+        Err(_err) => Color {
+            red: 0,
+            green: 0,
+            blue: 0,
+        },
+    };
+
+    let shutdown_fade = match env::var("SHUTDOWN_FADE") {
+        Ok(val) => Duration::from_millis(val.parse().unwrap()),
+        Err(_err) => Duration::ZERO,
+    };
+
+    let lights_shutdown = Arc::clone(&lights);
+    let tasks_shutdown = Arc::clone(&tasks);
+
+    let tasks_ws = Arc::clone(&tasks);
+    let tasks_osc = Arc::clone(&tasks);
+    let tasks_mqtt = Arc::clone(&tasks);
+    let tasks_wled = Arc::clone(&tasks);
+    let tasks_e131 = Arc::clone(&tasks);
+    let tasks_artnet = Arc::clone(&tasks);
+    let tasks_opc = Arc::clone(&tasks);
+    let tasks_audio = Arc::clone(&tasks);
+    #[cfg(feature = "alsa")]
+    let tasks_midi = Arc::clone(&tasks);
+    let tasks_output = Arc::clone(&tasks);
+
+    // rocket logs through tracing, so its own logger and level are kept out of the way
+    rocket::custom(tls::tls_figment(
+        Config::figment()
+            .merge((
+                "address",
+                (if cfg!(debug_assertions) {
+                    "127.0.0.1"
+                } else {
+                    "0.0.0.0"
+                }),
+            ))
+            .merge(("log_level", LogLevel::Off)),
+    ))
     .mount(
         "/",
         routes![
@@ -714,36 +1069,307 @@ fn rocket() -> _ {
             set_color,
             get_pattern,
             set_pattern,
-            ws_info,
+            get_bpm,
+            set_bpm,
+            tap,
+            undo,
+            redo,
+            metrics::metrics,
+            history::history,
+            status::health,
+            status::status,
+            ws::ws_info,
+            ws::websocket,
             files,
             service_worker,
             manifest,
             form,
-            form_submit
+            form_submit,
+            wled::wled_get,
+            wled::wled_post,
+            wled::wled_get_state,
+            wled::wled_set_state,
+            wled::wled_get_info,
+            wled::wled_get_effects,
+            wled::wled_get_palettes
+        ],
+    )
+    .register(
+        "/",
+        catchers![
+            bad_request,
+            unauthorized,
+            forbidden,
+            unprocessable_entity,
+            not_found
         ],
     )
-    .register("/", catchers![bad_request, unprocessable_entity, not_found])
+    .manage(tokens)
     .manage(lights_rocket)
+    .manage(metrics_rocket)
+    .manage(tasks)
     .attach(Template::fairing())
+    .attach(AdHoc::on_liftoff("Logging", |_rocket| {
+        Box::pin(async move {
+            // rocket caps the log crate at its own level if it installs its logger, which would
+            // hide rocket and dependency records that LOG_LEVEL lets through
+            log::set_max_level(log::LevelFilter::Trace);
+        })
+    }))
+    .attach(AdHoc::on_response(
+        "HTTP Metrics",
+        move |request, response| {
+            metrics_http.http_request(request, response);
+
+            Box::pin(async move {})
+        },
+    ))
     .attach(AdHoc::on_liftoff("WebSocket Server", move |_rocket| {
         Box::pin(async move {
-            tokio::spawn(async move {
-                ws_server(lights_ws, chronon).await;
-            });
+            // the standalone listener is only needed for clients that cannot use the HTTP port
+            if env::var("WS_PORT").is_ok() {
+                tasks_ws.spawn("ws", async move {
+                    ws::ws_server(tokens_ws, lights_ws, metrics_ws).await;
+                });
+            }
         })
     }))
     .attach(AdHoc::on_liftoff("OSC Server", move |_rocket| {
         Box::pin(async move {
-            tokio::spawn(async move {
-                osc_server(lights_osc).await;
+            tasks_osc.spawn("osc", async move {
+                osc::osc_server(lights_osc, metrics_osc).await;
             });
         })
     }))
+    .attach(AdHoc::on_liftoff("MQTT Client", move |_rocket| {
+        Box::pin(async move {
+            if env::var("MQTT_HOST").is_ok() {
+                tasks_mqtt.spawn("mqtt", async move {
+                    mqtt::mqtt_client(lights_mqtt).await;
+                });
+            }
+        })
+    }))
+    .attach(AdHoc::on_liftoff("WLED Realtime Server", move |_rocket| {
+        Box::pin(async move {
+            if env::var("WLED_UDP_PORT").is_ok() {
+                tasks_wled.spawn("wled", async move {
+                    wled::wled_realtime_server(lights_wled).await;
+                });
+            }
+        })
+    }))
+    .attach(AdHoc::on_liftoff("E1.31 Server", move |_rocket| {
+        Box::pin(async move {
+            if env::var("E131_UNIVERSE").is_ok() {
+                tasks_e131.spawn("e131", async move {
+                    dmx::e131_server(lights_e131).await;
+                });
+            }
+        })
+    }))
+    .attach(AdHoc::on_liftoff("Art-Net Server", move |_rocket| {
+        Box::pin(async move {
+            if env::var("ARTNET_UNIVERSE").is_ok() {
+                tasks_artnet.spawn("artnet", async move {
+                    dmx::artnet_server(lights_artnet).await;
+                });
+            }
+        })
+    }))
+    .attach(AdHoc::on_liftoff("OPC Server", move |_rocket| {
+        Box::pin(async move {
+            if env::var("OPC_PORT").is_ok() {
+                tasks_opc.spawn("opc", async move {
+                    opc::opc_server(lights_opc).await;
+                });
+            }
+        })
+    }))
+    .attach(AdHoc::on_liftoff("MIDI Input", move |_rocket| {
+        Box::pin(async move {
+            #[cfg(feature = "alsa")]
+            if env::var("MIDI_MAPPING").is_ok() {
+                tasks_midi.spawn("midi", async move {
+                    midi::midi_server(lights_midi).await;
+                });
+            }
+        })
+    }))
+    .attach(AdHoc::on_liftoff("Audio Input", move |_rocket| {
+        Box::pin(async move {
+            if env::var("AUDIO_SOURCE").is_ok() {
+                tasks_audio.spawn_finite("audio", audio::audio_server(lights_audio));
+            }
+        })
+    }))
     .attach(AdHoc::on_liftoff("Light Pattern Output", move |_rocket| {
         Box::pin(async move {
-            tokio::spawn(async move {
-                pattern_output(lights_output, chronon).await;
+            tasks_output.spawn("pattern", async move {
+                pattern_output(lights_output, metrics_output, chronon).await;
             });
         })
     }))
+    .attach(AdHoc::on_shutdown(
+        "Light Output Shutdown",
+        move |_rocket| {
+            Box::pin(async move {
+                shutdown(
+                    lights_shutdown,
+                    tasks_shutdown,
+                    shutdown_color,
+                    shutdown_fade,
+                )
+                .await;
+            })
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lights() -> Lights {
+        Lights::new(Output::Simulated, Pattern::Off, History::from_env())
+    }
+
+    fn solid(red: u8) -> Pattern {
+        Pattern::Solid(Color {
+            red,
+            green: 0,
+            blue: 0,
+        })
+    }
+
+    fn http(peer: &str) -> Source {
+        Source::Http(peer.parse().unwrap())
+    }
+
+    #[test]
+    fn undo_merges_changes_from_the_same_origin() {
+        let mut lights = lights();
+
+        // a slider dragged from one host, even across connections
+        lights.set_pattern(&solid(1), http("10.0.0.1:1000"));
+        lights.set_pattern(&solid(2), http("10.0.0.1:1000"));
+        lights.set_pattern(&solid(3), http("10.0.0.1:2000"));
+
+        assert_eq!(lights.undo.len(), 1);
+
+        // another host or another interface is a separate change
+        lights.set_pattern(&solid(4), http("10.0.0.2:1000"));
+        lights.set_pattern(&solid(5), Source::Osc("10.0.0.2:1000".parse().unwrap()));
+
+        assert_eq!(lights.undo.len(), 3);
+
+        assert!(lights.undo(Source::Midi));
+        assert!(lights.undo(Source::Midi));
+        assert!(lights.undo(Source::Midi));
+        assert!(*lights.get_pattern() == Pattern::Off);
+        assert!(!lights.undo(Source::Midi));
+    }
+
+    #[test]
+    fn undo_does_not_merge_after_a_pause() {
+        let mut lights = lights();
+
+        lights.set_pattern(&solid(1), Source::Mqtt);
+
+        lights.undo_last = lights
+            .undo_last
+            .map(|(source, instant)| (source, instant - UNDO_MERGE));
+
+        lights.set_pattern(&solid(2), Source::Mqtt);
+
+        assert_eq!(lights.undo.len(), 2);
+    }
+
+    #[test]
+    fn unchanged_patterns_not_recorded() {
+        let mut lights = lights();
+
+        lights.set_pattern(&Pattern::Off, Source::Mqtt);
+
+        assert!(lights.undo.is_empty());
+        assert_eq!(lights.get_history().entries().len(), 0);
+
+        lights.set_pattern(&solid(1), Source::Mqtt);
+        lights.set_pattern(&solid(1), Source::Mqtt);
+
+        assert_eq!(lights.get_history().entries().len(), 1);
+    }
+
+    #[test]
+    fn redo_cleared_by_a_new_change() {
+        let mut lights = lights();
+
+        lights.set_pattern(&solid(1), Source::Mqtt);
+
+        assert!(lights.undo(Source::Mqtt));
+        assert!(lights.redo(Source::Mqtt));
+        assert!(*lights.get_pattern() == solid(1));
+
+        assert!(lights.undo(Source::Mqtt));
+        lights.set_pattern(&solid(2), Source::Midi);
+
+        assert!(!lights.redo(Source::Midi));
+        assert!(*lights.get_pattern() == solid(2));
+    }
+
+    #[test]
+    fn undo_history_capped() {
+        let mut lights = lights();
+
+        for red in 1..=UNDO_HISTORY as u8 + 8 {
+            lights.set_pattern(&solid(red), Source::Midi);
+            lights.undo_last = None;
+        }
+
+        assert_eq!(lights.undo.len(), UNDO_HISTORY);
+
+        while lights.undo(Source::Midi) {}
+
+        // the oldest changes fell off the end
+        assert!(*lights.get_pattern() == solid(8));
+    }
+
+    #[test]
+    fn tap_averages_recent_taps() {
+        let mut lights = lights();
+
+        let now = Instant::now();
+
+        lights.taps.extend([
+            now - Duration::from_millis(1500),
+            now - Duration::from_millis(1000),
+            now - Duration::from_millis(500),
+        ]);
+
+        // the interval to this tap is a few microseconds longer than the others
+        assert!((lights.tap() - 120.0).abs() < 0.1);
+        assert_eq!(lights.taps.len(), 4);
+
+        // only the most recent taps count once there are more than the history holds
+        lights.taps = (1..=TAP_HISTORY as u32)
+            .rev()
+            .map(|taps| now - Duration::from_millis(250) * taps)
+            .collect();
+
+        assert!((lights.tap() - 240.0).abs() < 1.0);
+        assert_eq!(lights.taps.len(), TAP_HISTORY);
+    }
+
+    #[test]
+    fn tap_resets_after_a_pause() {
+        let mut lights = lights();
+
+        lights.taps.push_back(Instant::now() - TAP_TIMEOUT * 2);
+
+        // a single tap has no interval to set the tempo from
+        assert_eq!(lights.tap(), BPM_DEFAULT);
+        assert_eq!(lights.taps.len(), 1);
+
+        assert_eq!(lights.tap(), BPM_MAX);
+    }
 }
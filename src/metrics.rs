@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rocket::tokio::time::{Duration, Instant};
+use rocket::{Request, Response, State};
+
+use crate::auth::{Authorized, ReadOnly};
+use crate::{Color, SharedLights};
+
+const PATTERN_TYPES: [&str; 4] = ["off", "solid", "custom", "audio"];
+
+// frame timing of the pattern output, summarized every second
+#[derive(Clone, Copy)]
+pub struct FrameWindow {
+    pub fps: f64,
+    // how late frames started after they were due
+    pub jitter_mean: Duration,
+    pub jitter_max: Duration,
+    pub updated: Instant,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    frames: AtomicU64,
+    overruns: AtomicU64,
+    window: Mutex<Option<FrameWindow>>,
+
+    ws_clients: AtomicUsize,
+
+    // keyed by the matched OSC address so arbitrary addresses cannot grow the map
+    osc_received: Mutex<BTreeMap<&'static str, u64>>,
+    osc_rejected: Mutex<BTreeMap<&'static str, u64>>,
+    osc_denied: AtomicU64,
+
+    // keyed by method, route and status
+    http_requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+}
+
+pub type SharedMetrics = Arc<Metrics>;
+
+impl Metrics {
+    pub fn frame(&self, overrun: bool) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+
+        if overrun {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    pub fn window(&self) -> Option<FrameWindow> {
+        *self.window.lock().unwrap()
+    }
+
+    pub fn set_window(&self, window: FrameWindow) {
+        *self.window.lock().unwrap() = Some(window);
+    }
+
+    pub fn ws_connected(&self) {
+        self.ws_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ws_disconnected(&self) {
+        self.ws_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn osc_message(&self, addr: &'static str, accepted: bool) {
+        let counts = if accepted {
+            &self.osc_received
+        } else {
+            &self.osc_rejected
+        };
+
+        *counts.lock().unwrap().entry(addr).or_default() += 1;
+    }
+
+    // packets dropped before decoding because the source is not in the allowlist
+    pub fn osc_denied(&self) {
+        self.osc_denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn http_request(&self, request: &Request<'_>, response: &Response<'_>) {
+        let route = match request.route() {
+            Some(route) => route.uri.to_string(),
+            None => String::from("unmatched"),
+        };
+
+        *self
+            .http_requests
+            .lock()
+            .unwrap()
+            .entry((request.method().to_string(), route, response.status().code))
+            .or_default() += 1;
+    }
+
+    fn render(&self, output: Color, pattern: &str) -> String {
+        let mut text = String::new();
+
+        header(
+            &mut text,
+            "lights_duty_cycle",
+            "gauge",
+            "PWM duty cycle currently output on each channel.",
+        );
+        for (channel, value) in [
+            ("red", output.red),
+            ("green", output.green),
+            ("blue", output.blue),
+        ] {
+            text += &format!(
+                "lights_duty_cycle{{channel=\"{}\"}} {}\n",
+                channel,
+                value as f64 / 255.0
+            );
+        }
+
+        header(&mut text, "lights_pattern", "gauge", "Active pattern type.");
+        for name in PATTERN_TYPES {
+            text += &format!(
+                "lights_pattern{{type=\"{}\"}} {}\n",
+                name,
+                (name == pattern) as u8
+            );
+        }
+
+        header(
+            &mut text,
+            "lights_output_fps",
+            "gauge",
+            "Frames per second achieved by the pattern output over the last second.",
+        );
+        let window = self.window();
+
+        text += &format!(
+            "lights_output_fps {}\n",
+            window.map_or(0.0, |window| window.fps)
+        );
+
+        header(
+            &mut text,
+            "lights_tick_jitter_seconds",
+            "gauge",
+            "How late frames started after they were due over the last second.",
+        );
+        if let Some(window) = window {
+            text += &format!(
+                "lights_tick_jitter_seconds{{stat=\"mean\"}} {}\n",
+                window.jitter_mean.as_secs_f64()
+            );
+            text += &format!(
+                "lights_tick_jitter_seconds{{stat=\"max\"}} {}\n",
+                window.jitter_max.as_secs_f64()
+            );
+        }
+
+        header(
+            &mut text,
+            "lights_output_frames_total",
+            "counter",
+            "Frames computed by the pattern output.",
+        );
+        text += &format!(
+            "lights_output_frames_total {}\n",
+            self.frames.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut text,
+            "lights_tick_overruns_total",
+            "counter",
+            "Frames that finished after the next frame was due.",
+        );
+        text += &format!("lights_tick_overruns_total {}\n", self.overruns());
+
+        header(
+            &mut text,
+            "lights_websocket_clients",
+            "gauge",
+            "Connected WebSocket clients.",
+        );
+        text += &format!(
+            "lights_websocket_clients {}\n",
+            self.ws_clients.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut text,
+            "lights_osc_messages_received_total",
+            "counter",
+            "OSC messages handled by address.",
+        );
+        for (addr, count) in self.osc_received.lock().unwrap().iter() {
+            text += &format!(
+                "lights_osc_messages_received_total{{address=\"{}\"}} {}\n",
+                addr, count
+            );
+        }
+
+        header(
+            &mut text,
+            "lights_osc_messages_rejected_total",
+            "counter",
+            "OSC messages with unexpected arguments or addresses.",
+        );
+        for (addr, count) in self.osc_rejected.lock().unwrap().iter() {
+            text += &format!(
+                "lights_osc_messages_rejected_total{{address=\"{}\"}} {}\n",
+                addr, count
+            );
+        }
+
+        header(
+            &mut text,
+            "lights_osc_packets_denied_total",
+            "counter",
+            "OSC packets from sources outside the allowlist.",
+        );
+        text += &format!(
+            "lights_osc_packets_denied_total {}\n",
+            self.osc_denied.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut text,
+            "lights_http_requests_total",
+            "counter",
+            "HTTP requests by method, route and status.",
+        );
+        for ((method, route, status), count) in self.http_requests.lock().unwrap().iter() {
+            text += &format!(
+                "lights_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}\n",
+                method,
+                route.replace('\\', "\\\\").replace('"', "\\\""),
+                status,
+                count
+            );
+        }
+
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    *text += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+}
+
+#[get("/metrics")]
+pub async fn metrics(
+    _auth: Authorized<ReadOnly>,
+    metrics: &State<SharedMetrics>,
+    lights: &State<SharedLights>,
+) -> String {
+    let (output, pattern) = {
+        let lights = lights.lock().await;
+
+        (lights.get_output(), lights.get_pattern().name())
+    };
+
+    metrics.render(output, pattern)
+}
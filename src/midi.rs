@@ -0,0 +1,243 @@
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::thread;
+
+use alsa::seq::{Addr, EvCtrl, EvNote, EventType, PortCap, PortSubscribe, PortType, Seq};
+use alsa::Direction;
+
+use rocket::serde::json::serde_json;
+use rocket::serde::Deserialize;
+
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::time::Duration;
+
+use tracing::{error, info, instrument};
+
+use yansi::Paint;
+
+use crate::history::Source;
+use crate::{Color, Pattern, SharedLights};
+
+// frequencies in hz that strobe actions are clamped to, as fast as lights can visibly flash
+const STROBE_FREQUENCY_MIN: f64 = 0.1;
+const STROBE_FREQUENCY_MAX: f64 = 50.0;
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+enum MidiTrigger {
+    Note(u8),
+    Control(u8),
+}
+
+#[derive(Deserialize)]
+#[serde(
+    crate = "rocket::serde",
+    rename_all = "lowercase",
+    tag = "type",
+    content = "content"
+)]
+enum MidiAction {
+    // set a solid color when pressed
+    Color(Color),
+    // set any pattern when pressed, to recall presets
+    Pattern(Pattern),
+    // turn the lights off when pressed
+    Off,
+    // strobe a color at a frequency in Hz while held
+    Strobe { color: Color, frequency: f64 },
+    // scale the color by note velocity or controller value
+    Brightness,
+    // set a single channel of the color from note velocity or controller value
+    Red,
+    Green,
+    Blue,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct MidiMapping {
+    // one-based midi channel, any channel if missing
+    channel: Option<u8>,
+    #[serde(flatten)]
+    trigger: MidiTrigger,
+    action: MidiAction,
+}
+
+impl MidiMapping {
+    fn matches(&self, event: &MidiEvent) -> bool {
+        self.channel
+            .is_none_or(|channel| channel == event.channel + 1)
+            && self.trigger == event.trigger
+    }
+}
+
+struct MidiEvent {
+    // zero-based midi channel as sent on the wire
+    channel: u8,
+    trigger: MidiTrigger,
+    // note velocity (zero for note off) or controller value
+    value: u8,
+}
+
+fn midi_input(sender: mpsc::Sender<MidiEvent>) -> alsa::Result<()> {
+    let seq = Seq::open(None, Some(Direction::Capture), false)?;
+
+    seq.set_client_name(&CString::new("Lights").unwrap())?;
+
+    let port = seq.create_simple_port(
+        &CString::new("Lights").unwrap(),
+        PortCap::WRITE | PortCap::SUBS_WRITE,
+        PortType::MIDI_GENERIC | PortType::APPLICATION,
+    )?;
+
+    let dest = Addr {
+        client: seq.client_id()?,
+        port,
+    };
+
+    // otherwise a source can be connected to the port with aconnect
+    if let Ok(source) = env::var("MIDI_SOURCE") {
+        let sender: Addr = source.parse().expect("Invalid MIDI source address");
+
+        let subscription = PortSubscribe::empty()?;
+
+        subscription.set_sender(sender);
+        subscription.set_dest(dest);
+
+        seq.subscribe_port(&subscription)?;
+    }
+
+    println!(
+        "{}{} {}",
+        Paint::masked("🎹 "),
+        Paint::default("MIDI input started on").bold(),
+        Paint::default(format!("{}:{}", dest.client, dest.port))
+            .bold()
+            .underline()
+    );
+
+    let mut input = seq.input();
+
+    loop {
+        let event = input.event_input()?;
+
+        let event = match event.get_type() {
+            EventType::Noteon | EventType::Noteoff => {
+                let note: EvNote = event.get_data().unwrap();
+
+                MidiEvent {
+                    channel: note.channel,
+                    trigger: MidiTrigger::Note(note.note),
+                    value: if event.get_type() == EventType::Noteon {
+                        note.velocity
+                    } else {
+                        0
+                    },
+                }
+            }
+            EventType::Controller => {
+                let ctrl: EvCtrl = event.get_data().unwrap();
+
+                MidiEvent {
+                    channel: ctrl.channel,
+                    trigger: MidiTrigger::Control(ctrl.param as u8),
+                    value: ctrl.value.clamp(0, 127) as u8,
+                }
+            }
+            _ => {
+                continue;
+            }
+        };
+
+        if sender.blocking_send(event).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[instrument(name = "midi", skip_all)]
+async fn midi_apply(action: &MidiAction, event: &MidiEvent, lights: &SharedLights) {
+    let mut lights = lights.lock().await;
+
+    // notes are pressed while they sound and controllers past their middle
+    let pressed = match event.trigger {
+        MidiTrigger::Note(_note) => event.value > 0,
+        MidiTrigger::Control(_control) => event.value >= 64,
+    };
+
+    let scaled = (event.value as u16 * 255 / 127) as u8;
+
+    let pattern = match action {
+        MidiAction::Color(color) => pressed.then_some(Pattern::Solid(*color)),
+        MidiAction::Pattern(pattern) => pressed.then(|| pattern.clone()),
+        MidiAction::Off => pressed.then_some(Pattern::Off),
+        MidiAction::Strobe { color, frequency } => {
+            if pressed {
+                info!(%color, frequency, "Started strobe");
+                lights.set_realtime(
+                    *color,
+                    Some(Duration::from_secs_f64(
+                        1.0 / frequency.clamp(STROBE_FREQUENCY_MIN, STROBE_FREQUENCY_MAX),
+                    )),
+                    None,
+                    Source::Midi,
+                );
+            } else {
+                info!("Stopped strobe");
+                lights.clear_realtime(Source::Midi);
+            }
+
+            None
+        }
+        MidiAction::Brightness => {
+            if scaled == 0 {
+                Some(Pattern::Off)
+            } else {
+                let (color, _brightness) = lights.get_on_pattern().color().split_brightness();
+
+                Some(Pattern::Solid(color.with_brightness(scaled)))
+            }
+        }
+        MidiAction::Red | MidiAction::Green | MidiAction::Blue => {
+            let mut color = lights.get_on_pattern().color();
+
+            match action {
+                MidiAction::Red => color.red = scaled,
+                MidiAction::Green => color.green = scaled,
+                _ => color.blue = scaled,
+            }
+
+            Some(Pattern::Solid(color))
+        }
+    };
+
+    if let Some(pattern) = pattern {
+        info!(pattern = pattern.name(), "Set pattern");
+        lights.set_pattern(&pattern, Source::Midi);
+    }
+}
+
+pub async fn midi_server(lights: SharedLights) {
+    let path = env::var("MIDI_MAPPING").expect("Missing MIDI mapping");
+
+    let mappings: Vec<MidiMapping> =
+        serde_json::from_str(&fs::read_to_string(&path).expect("Failed to read MIDI mapping file"))
+            .expect("Failed to parse MIDI mapping file");
+
+    let (sender, mut events) = mpsc::channel(64);
+
+    // the sequencer blocks on input so it gets its own thread
+    thread::spawn(move || match midi_input(sender) {
+        Ok(()) => {}
+        Err(err) => {
+            error!(error = %err, "MIDI input failure");
+        }
+    });
+
+    while let Some(event) = events.recv().await {
+        for mapping in mappings.iter().filter(|mapping| mapping.matches(&event)) {
+            midi_apply(&mapping.action, &event, &lights).await;
+        }
+    }
+}
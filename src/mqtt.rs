@@ -0,0 +1,354 @@
+use std::env;
+
+use rocket::serde::json::serde_json;
+use rocket::serde::json::serde_json::{json, Value};
+use rocket::serde::Deserialize;
+
+use rocket::tokio;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::time;
+use rocket::tokio::time::Duration;
+
+use tracing::{info, instrument, warn};
+
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+
+use yansi::Paint;
+
+use crate::history::Source;
+use crate::{Audio, Change, Color, Frame, Pattern, SharedLights};
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct MqttColor {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct MqttCommand {
+    state: Option<String>,
+    color: Option<MqttColor>,
+    brightness: Option<u8>,
+    effect: Option<String>,
+}
+
+enum MqttEvent {
+    Connected,
+    Command(MqttCommand),
+}
+
+struct MqttTopics {
+    command: String,
+    state: String,
+    availability: String,
+    discovery: String,
+}
+
+struct MqttMemory {
+    // last custom pattern to restore with the custom effect
+    custom: Option<Vec<Frame>>,
+}
+
+impl MqttMemory {
+    fn remember(&mut self, pattern: &Pattern) {
+        if let Pattern::Custom(frames) = pattern {
+            self.custom = Some(frames.clone());
+        }
+    }
+}
+
+fn mqtt_state(pattern: &Pattern) -> Value {
+    let effect = match pattern {
+        Pattern::Off => {
+            return json!({ "state": "OFF" });
+        }
+        Pattern::Solid(_color) => "solid",
+        Pattern::Custom(_frames) => "custom",
+        Pattern::Audio(_audio) => "audio",
+    };
+
+    let (color, brightness) = pattern.color().split_brightness();
+
+    json!({
+        "state": "ON",
+        "color_mode": "rgb",
+        "brightness": brightness,
+        "color": {
+            "r": color.red,
+            "g": color.green,
+            "b": color.blue,
+        },
+        "effect": effect,
+    })
+}
+
+fn mqtt_discovery(client_id: &str, topics: &MqttTopics) -> Value {
+    json!({
+        "name": null,
+        "unique_id": client_id,
+        "object_id": client_id,
+        "schema": "json",
+        "command_topic": topics.command,
+        "state_topic": topics.state,
+        "availability_topic": topics.availability,
+        "supported_color_modes": ["rgb"],
+        "brightness": true,
+        "effect": true,
+        "effect_list": ["solid", "custom", "audio"],
+        "device": {
+            "identifiers": [client_id],
+            "name": "Lights",
+            "model": env!("CARGO_PKG_NAME"),
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
+
+async fn mqtt_publish(client: &AsyncClient, topic: &str, payload: String) {
+    match client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+        Ok(()) => {}
+        Err(err) => {
+            warn!(topic, error = %err, "Failed to publish MQTT message");
+        }
+    }
+}
+
+async fn mqtt_event_loop(
+    mut eventloop: EventLoop,
+    command_topic: String,
+    events: mpsc::Sender<MqttEvent>,
+) {
+    // the event loop is only polled here so nothing else can cancel a connect or write in flight
+    loop {
+        let event = match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_connack))) => MqttEvent::Connected,
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == command_topic => {
+                match serde_json::from_slice::<MqttCommand>(&publish.payload) {
+                    Ok(command) => MqttEvent::Command(command),
+                    Err(err) => {
+                        warn!(error = %err, "Failed to parse MQTT command");
+                        continue;
+                    }
+                }
+            }
+            Ok(_) => {
+                // ignore other events
+                continue;
+            }
+            Err(err) => {
+                // polling again reconnects so just avoid hammering the broker
+                warn!(error = %err, "MQTT connection error");
+                time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        // waiting for the client task here could deadlock with it waiting to publish through us
+        match events.try_send(event) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_event)) => {
+                warn!("Dropping MQTT event with too many already pending");
+            }
+            Err(mpsc::error::TrySendError::Closed(_event)) => {
+                break;
+            }
+        }
+    }
+}
+
+#[instrument(name = "mqtt", skip_all)]
+async fn mqtt_command(command: MqttCommand, lights: &SharedLights, memory: &MqttMemory) {
+    if command.state.as_deref() == Some("OFF") {
+        info!(pattern = Pattern::Off.name(), "Set pattern");
+        lights.lock().await.set_pattern(&Pattern::Off, Source::Mqtt);
+
+        return;
+    }
+
+    let (current, on) = {
+        let lights = lights.lock().await;
+
+        (
+            lights.get_pattern().clone(),
+            lights.get_on_pattern().clone(),
+        )
+    };
+
+    let mut pattern = match command.effect.as_deref() {
+        Some("solid") => Pattern::Solid(on.color()),
+        Some("custom") => match &memory.custom {
+            Some(frames) => Pattern::Custom(frames.clone()),
+            None => {
+                warn!("No custom pattern to restore for MQTT custom effect");
+                return;
+            }
+        },
+        Some("audio") => match on {
+            Pattern::Audio(_) => on,
+            _ => Pattern::Audio(Audio::new(1.0)),
+        },
+        Some(effect) => {
+            warn!(effect, "Unexpected MQTT effect");
+            return;
+        }
+        None => match current {
+            Pattern::Off => on,
+            _ => current,
+        },
+    };
+
+    if command.color.is_some() || command.brightness.is_some() {
+        let (color, brightness) = pattern.color().split_brightness();
+
+        let color = match command.color {
+            Some(color) => Color {
+                red: color.r,
+                green: color.g,
+                blue: color.b,
+            },
+            None => color,
+        };
+
+        let brightness = match command.brightness {
+            Some(brightness) => brightness,
+            None if brightness == 0 => 255,
+            None => brightness,
+        };
+
+        pattern = Pattern::Solid(color.with_brightness(brightness));
+    }
+
+    info!(pattern = pattern.name(), "Set pattern");
+    lights.lock().await.set_pattern(&pattern, Source::Mqtt);
+}
+
+pub async fn mqtt_client(lights: SharedLights) {
+    let host = env::var("MQTT_HOST").expect("Missing MQTT host");
+
+    let port: u16 = match env::var("MQTT_PORT") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 1883,
+    };
+
+    let client_id = match env::var("MQTT_CLIENT_ID") {
+        Ok(val) => val,
+        Err(_err) => String::from("fooster_lights"),
+    };
+
+    let base_topic = match env::var("MQTT_TOPIC") {
+        Ok(val) => val,
+        Err(_err) => String::from("lights"),
+    };
+
+    let discovery_prefix = match env::var("MQTT_DISCOVERY_PREFIX") {
+        Ok(val) => val,
+        Err(_err) => String::from("homeassistant"),
+    };
+
+    let topics = MqttTopics {
+        command: format!("{}/set", base_topic),
+        state: format!("{}/state", base_topic),
+        availability: format!("{}/availability", base_topic),
+        discovery: format!("{}/light/{}/config", discovery_prefix, client_id),
+    };
+
+    let mut options = MqttOptions::new(client_id.as_str(), host.as_str(), port);
+
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        topics.availability.as_str(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    if let Ok(username) = env::var("MQTT_USERNAME") {
+        options.set_credentials(username, env::var("MQTT_PASSWORD").unwrap_or_default());
+    }
+
+    let (client, eventloop) = AsyncClient::new(options, 16);
+
+    let (events_tx, mut events) = mpsc::channel(16);
+
+    tokio::spawn(mqtt_event_loop(
+        eventloop,
+        topics.command.clone(),
+        events_tx,
+    ));
+
+    println!(
+        "{}{} {}",
+        Paint::masked("📡 "),
+        Paint::default("MQTT client started for").bold(),
+        Paint::default(format!("mqtt://{}:{}", host, port))
+            .bold()
+            .underline()
+    );
+
+    let (mut changes, mut memory) = {
+        let lights = lights.lock().await;
+
+        let mut memory = MqttMemory { custom: None };
+
+        memory.remember(lights.get_pattern());
+
+        (lights.subscribe(), memory)
+    };
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Some(MqttEvent::Connected) => {
+                        // subscriptions and retained messages are renewed on every (re)connection
+                        match client.subscribe(topics.command.as_str(), QoS::AtLeastOnce).await {
+                            Ok(()) => {},
+                            Err(err) => {
+                                warn!(topic = topics.command, error = %err, "Failed to subscribe to MQTT topic");
+                            }
+                        }
+
+                        let state = mqtt_state(lights.lock().await.get_pattern()).to_string();
+
+                        mqtt_publish(&client, &topics.discovery, mqtt_discovery(&client_id, &topics).to_string()).await;
+                        mqtt_publish(&client, &topics.availability, String::from("online")).await;
+                        mqtt_publish(&client, &topics.state, state).await;
+                    },
+                    Some(MqttEvent::Command(command)) => {
+                        mqtt_command(command, &lights, &memory).await;
+                    },
+                    None => {
+                        break;
+                    },
+                }
+            }
+
+            change = changes.recv() => {
+                match change {
+                    Ok(Change::Pattern { pattern, .. }) => {
+                        memory.remember(&pattern);
+
+                        mqtt_publish(&client, &topics.state, mqtt_state(&pattern).to_string()).await;
+                    },
+                    Ok(Change::Frame { .. }) => {
+                        // frames of running patterns are not published to keep the broker quiet
+                    },
+                    Err(RecvError::Lagged(_skipped)) => {
+                        let pattern = lights.lock().await.get_pattern().clone();
+
+                        memory.remember(&pattern);
+
+                        mqtt_publish(&client, &topics.state, mqtt_state(&pattern).to_string()).await;
+                    },
+                    Err(RecvError::Closed) => {
+                        break;
+                    },
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use rocket::tokio;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::net::{TcpListener, TcpStream};
+
+use tracing::{instrument, warn};
+
+use yansi::Paint;
+
+use crate::auth;
+use crate::history::Source;
+use crate::{Color, SharedLights};
+
+const OPC_SET_PIXELS: u8 = 0;
+const OPC_BROADCAST: u8 = 0;
+
+#[instrument(name = "opc", skip_all, fields(%peer))]
+async fn opc_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    channel: u8,
+    lights: SharedLights,
+) {
+    let mut header = [0u8; 4];
+    let mut data = vec![0u8; u16::MAX as usize];
+
+    // closing between messages is the normal way to disconnect
+    while let Ok(_size) = stream.read_exact(&mut header).await {
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+        match stream.read_exact(&mut data[..length]).await {
+            Ok(_size) => {}
+            Err(err) => {
+                warn!(error = %err, "Error receiving from OPC connection");
+                break;
+            }
+        }
+
+        if header[0] != OPC_BROADCAST && header[0] != channel {
+            continue;
+        }
+
+        // system exclusive and other commands are not supported
+        if header[1] != OPC_SET_PIXELS {
+            continue;
+        }
+
+        // only the first pixel exists on a single-color strip
+        let color = match data[..length] {
+            [red, green, blue, ..] => Color { red, green, blue },
+            _ => {
+                continue;
+            }
+        };
+
+        lights
+            .lock()
+            .await
+            .set_realtime(color, None, None, Source::Opc(peer));
+    }
+
+    // the pattern resumes unless another client or protocol has taken over since
+    lights.lock().await.release_realtime(Source::Opc(peer));
+}
+
+pub async fn opc_server(lights: SharedLights) {
+    let address = match env::var("OPC_ADDRESS") {
+        Ok(val) => val,
+        Err(_err) => String::from(if cfg!(debug_assertions) {
+            "127.0.0.1"
+        } else {
+            "0.0.0.0"
+        }),
+    };
+
+    let port: u16 = env::var("OPC_PORT")
+        .expect("Missing OPC port")
+        .parse()
+        .unwrap();
+
+    let channel: u8 = match env::var("OPC_CHANNEL") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 1,
+    };
+
+    let allowlist = auth::allowlist_from_env("OPC_ALLOW");
+
+    let listener = TcpListener::bind((address, port))
+        .await
+        .expect("Failed to bind TCP OPC address");
+
+    println!(
+        "{}{} {}",
+        Paint::masked("🎨 "),
+        Paint::default("OPC server started on").bold(),
+        Paint::default(listener.local_addr().unwrap())
+            .bold()
+            .underline()
+    );
+
+    loop {
+        match listener.accept().await {
+            Ok((_stream, peer)) if !auth::allowed(&allowlist, peer.ip()) => {
+                warn!(%peer, "Rejected OPC connection");
+            }
+            Ok((stream, peer)) => {
+                let lights_conn = Arc::clone(&lights);
+
+                tokio::spawn(async move {
+                    opc_connection(stream, peer, channel, lights_conn).await;
+                });
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to accept OPC connection");
+            }
+        }
+    }
+}
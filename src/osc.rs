@@ -0,0 +1,1153 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rocket::serde::json::serde_json;
+
+use rocket::tokio;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::net::{TcpListener, TcpStream, UdpSocket};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::time;
+use rocket::tokio::time::{Duration, Instant};
+
+use rosc::address::{Matcher, OscAddress};
+use rosc::{OscMessage, OscPacket, OscTime, OscType};
+
+use tracing::{debug, error, info, instrument, warn};
+
+use yansi::Paint;
+
+use crate::auth;
+use crate::history::Source;
+use crate::metrics::SharedMetrics;
+use crate::{Audio, Change, Color, Frame, Pattern, SharedLights, Timing};
+
+fn osc_duration(arg: &OscType) -> Option<Duration> {
+    match arg {
+        OscType::Int(millis) if *millis >= 0 => Some(Duration::from_millis(*millis as u64)),
+        OscType::Long(millis) if *millis >= 0 => Some(Duration::from_millis(*millis as u64)),
+        // rejects negative, non-finite, and unrepresentably long durations
+        OscType::Float(millis) => Duration::try_from_secs_f64(*millis as f64 / 1000.0).ok(),
+        OscType::Double(millis) => Duration::try_from_secs_f64(*millis / 1000.0).ok(),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum OscFloatMode {
+    // floats from 0.0 to 1.0 like most OSC controllers send
+    Normalized,
+    // floats from 0.0 to 255.0 like the integer arguments
+    Legacy,
+}
+
+fn osc_channel(arg: &OscType, float_mode: OscFloatMode) -> Option<u8> {
+    let scale = match float_mode {
+        OscFloatMode::Normalized => 255.0,
+        OscFloatMode::Legacy => 1.0,
+    };
+
+    match arg {
+        OscType::Int(value) => Some((*value).clamp(0, 255) as u8),
+        OscType::Long(value) => Some((*value).clamp(0, 255) as u8),
+        OscType::Float(value) => Some((*value as f64 * scale).round().clamp(0.0, 255.0) as u8),
+        OscType::Double(value) => Some((*value * scale).round().clamp(0.0, 255.0) as u8),
+        _ => None,
+    }
+}
+
+fn osc_color(args: &[OscType], float_mode: OscFloatMode) -> Option<(Color, &[OscType])> {
+    match args {
+        [OscType::Color(color), rest @ ..] => Some((
+            Color {
+                red: color.red,
+                green: color.green,
+                blue: color.blue,
+            },
+            rest,
+        )),
+        [red, green, blue, rest @ ..] => Some((
+            Color {
+                red: osc_channel(red, float_mode)?,
+                green: osc_channel(green, float_mode)?,
+                blue: osc_channel(blue, float_mode)?,
+            },
+            rest,
+        )),
+        _ => None,
+    }
+}
+
+fn osc_bpm(args: &[OscType]) -> Option<f64> {
+    let bpm = match args {
+        [OscType::Int(bpm)] => *bpm as f64,
+        [OscType::Long(bpm)] => *bpm as f64,
+        [OscType::Float(bpm)] => *bpm as f64,
+        [OscType::Double(bpm)] => *bpm,
+        _ => {
+            return None;
+        }
+    };
+
+    if fooster_lights::valid_bpm(bpm) {
+        Some(bpm)
+    } else {
+        None
+    }
+}
+
+fn osc_frames(mut args: &[OscType], float_mode: OscFloatMode) -> Option<Vec<Frame>> {
+    let mut frames = Vec::new();
+
+    while !args.is_empty() {
+        let (color, rest) = osc_color(args, float_mode)?;
+        let (duration, rest) = rest.split_first()?;
+
+        frames.push(Frame {
+            color,
+            timing: Timing::Duration(osc_duration(duration)?),
+        });
+
+        args = rest;
+    }
+
+    Some(frames)
+}
+
+const OSC_ADDRESSES: &[&str] = &[
+    "/color",
+    "/color/red",
+    "/color/green",
+    "/color/blue",
+    "/brightness",
+    "/pattern",
+    "/pattern/off",
+    "/pattern/solid",
+    "/pattern/custom",
+    "/pattern/audio",
+    "/bpm",
+    "/tap",
+    "/undo",
+    "/redo",
+    "/get/color",
+    "/get/pattern",
+    "/get/bpm",
+    "/subscribe",
+    "/unsubscribe",
+];
+
+const OSC_TCP_MAX_PACKET: usize = 1 << 20;
+
+// udp sources can be spoofed, so subscriptions over udp expire unless renewed and are capped
+const OSC_SUBSCRIPTION_LEASE: Duration = Duration::from_secs(60);
+const OSC_SUBSCRIBERS_MAX: usize = 16;
+
+// bundles can only be scheduled so far ahead, and only so many at once
+const OSC_SCHEDULE_HORIZON: Duration = Duration::from_secs(24 * 60 * 60);
+const OSC_SCHEDULED_MAX: usize = 1024;
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(packet.len() + 2);
+
+    frame.push(SLIP_END);
+
+    for byte in packet {
+        match *byte {
+            SLIP_END => frame.extend([SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => frame.extend([SLIP_ESC, SLIP_ESC_ESC]),
+            byte => frame.push(byte),
+        }
+    }
+
+    frame.push(SLIP_END);
+
+    frame
+}
+
+#[derive(Default)]
+struct SlipDecoder {
+    frame: Vec<u8>,
+    escaped: bool,
+    overflowed: bool,
+}
+
+impl SlipDecoder {
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        let byte = match (self.escaped, byte) {
+            (false, SLIP_END) => {
+                let frame = std::mem::take(&mut self.frame);
+                let overflowed = std::mem::replace(&mut self.overflowed, false);
+
+                if overflowed {
+                    warn!(limit = OSC_TCP_MAX_PACKET, "Dropped oversized OSC packet");
+                }
+
+                // frames may be delimited on both ends so empty frames are expected
+                return Some(frame).filter(|frame| !frame.is_empty() && !overflowed);
+            }
+            (false, SLIP_ESC) => {
+                self.escaped = true;
+
+                return None;
+            }
+            (false, byte) => byte,
+            (true, SLIP_ESC_END) => SLIP_END,
+            (true, SLIP_ESC_ESC) => SLIP_ESC,
+            (true, byte) => byte,
+        };
+
+        self.escaped = false;
+
+        if self.frame.len() < OSC_TCP_MAX_PACKET {
+            self.frame.push(byte);
+        } else {
+            self.overflowed = true;
+        }
+
+        None
+    }
+}
+
+#[derive(Clone)]
+enum OscPeer {
+    Udp(SocketAddr),
+    Tcp(SocketAddr, mpsc::UnboundedSender<Vec<u8>>),
+}
+
+impl OscPeer {
+    fn addr(&self) -> SocketAddr {
+        match self {
+            OscPeer::Udp(addr) => *addr,
+            OscPeer::Tcp(addr, _) => *addr,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            OscPeer::Udp(_) => false,
+            OscPeer::Tcp(_, sender) => sender.is_closed(),
+        }
+    }
+}
+
+struct OscSubscriber {
+    peer: OscPeer,
+    // tcp subscriptions last as long as the connection instead
+    expires: Option<Instant>,
+}
+
+impl OscSubscriber {
+    fn new(peer: OscPeer) -> OscSubscriber {
+        let expires = match peer {
+            OscPeer::Udp(_) => Some(Instant::now() + OSC_SUBSCRIPTION_LEASE),
+            OscPeer::Tcp(_, _) => None,
+        };
+
+        OscSubscriber { peer, expires }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.peer.is_closed()
+            || self
+                .expires
+                .is_some_and(|expires| expires <= Instant::now())
+    }
+}
+
+type OscSubscribers = HashMap<SocketAddr, OscSubscriber>;
+
+// subscribing again renews the lease, but new subscribers are turned away once full
+fn osc_subscribe(subscribers: &mut OscSubscribers, addr: SocketAddr, peer: OscPeer) -> bool {
+    subscribers.retain(|_, subscriber| !subscriber.is_expired());
+
+    if subscribers.len() >= OSC_SUBSCRIBERS_MAX && !subscribers.contains_key(&addr) {
+        return false;
+    }
+
+    subscribers.insert(addr, OscSubscriber::new(peer));
+
+    true
+}
+
+async fn osc_send(socket: &UdpSocket, peer: &OscPeer, addr: &str, args: Vec<OscType>) {
+    let packet = OscPacket::Message(OscMessage {
+        addr: String::from(addr),
+        args,
+    });
+
+    match rosc::encoder::encode(&packet) {
+        Ok(buffer) => match peer {
+            OscPeer::Udp(peer) => match socket.send_to(&buffer, peer).await {
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(%peer, address = addr, error = %err, "Error sending OSC message");
+                }
+            },
+            OscPeer::Tcp(peer, sender) => match sender.send(slip_encode(&buffer)) {
+                Ok(_) => {}
+                Err(_err) => {
+                    warn!(%peer, address = addr, "Error sending OSC message: connection closed");
+                }
+            },
+        },
+        Err(err) => {
+            error!(address = addr, error = ?err, "Error encoding OSC message");
+        }
+    }
+}
+
+async fn osc_send_color(socket: &UdpSocket, peer: &OscPeer, color: Color) {
+    osc_send(
+        socket,
+        peer,
+        "/color",
+        vec![
+            OscType::Int(color.red as i32),
+            OscType::Int(color.green as i32),
+            OscType::Int(color.blue as i32),
+        ],
+    )
+    .await;
+}
+
+async fn osc_send_pattern(socket: &UdpSocket, peer: &OscPeer, pattern: &Pattern) {
+    osc_send(
+        socket,
+        peer,
+        "/pattern",
+        vec![OscType::String(serde_json::to_string(pattern).unwrap())],
+    )
+    .await;
+}
+
+#[instrument(name = "osc", skip_all, fields(source = %peer.addr(), address = %msg.addr))]
+async fn osc_message(
+    msg: &OscMessage,
+    peer: &OscPeer,
+    socket: &UdpSocket,
+    subscribers: &mut OscSubscribers,
+    float_mode: OscFloatMode,
+    lights: &SharedLights,
+    metrics: &SharedMetrics,
+) {
+    let matcher = match Matcher::new(&msg.addr) {
+        Ok(matcher) => matcher,
+        Err(err) => {
+            warn!(error = ?err, "Invalid OSC address pattern");
+            metrics.osc_message("invalid", false);
+            return;
+        }
+    };
+
+    debug!(args = ?msg.args, "Received OSC message");
+
+    let mut matched = false;
+
+    for addr in OSC_ADDRESSES {
+        if matcher.match_address(&OscAddress::new(String::from(*addr)).unwrap()) {
+            matched = true;
+
+            let accepted =
+                osc_dispatch(addr, msg, peer, socket, subscribers, float_mode, lights).await;
+
+            // queries and subscriptions do not change the lights
+            if accepted && !addr.starts_with("/get/") && !addr.ends_with("subscribe") {
+                info!(command = addr, args = ?msg.args, "Handled OSC command");
+            }
+
+            metrics.osc_message(addr, accepted);
+        }
+    }
+
+    if !matched {
+        warn!(args = ?msg.args, "Unexpected OSC message");
+        metrics.osc_message("unmatched", false);
+    }
+}
+
+async fn osc_dispatch(
+    addr: &str,
+    msg: &OscMessage,
+    peer: &OscPeer,
+    socket: &UdpSocket,
+    subscribers: &mut OscSubscribers,
+    float_mode: OscFloatMode,
+    lights: &SharedLights,
+) -> bool {
+    let source = Source::Osc(peer.addr());
+
+    match addr {
+        "/color" => match osc_color(&msg.args, float_mode) {
+            Some((color, [])) => {
+                lights.lock().await.set(color, source);
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /color command");
+                return false;
+            }
+        },
+        "/color/red" => match &msg.args[..] {
+            [arg] => match osc_channel(arg, float_mode) {
+                Some(red) => {
+                    let mut lights = lights.lock().await;
+                    let color = lights.get();
+
+                    lights.set(Color { red, ..color }, source);
+                }
+                None => {
+                    warn!(args = ?msg.args, "Unexpected OSC /color/red command");
+                    return false;
+                }
+            },
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /color/red command");
+                return false;
+            }
+        },
+        "/color/green" => match &msg.args[..] {
+            [arg] => match osc_channel(arg, float_mode) {
+                Some(green) => {
+                    let mut lights = lights.lock().await;
+                    let color = lights.get();
+
+                    lights.set(Color { green, ..color }, source);
+                }
+                None => {
+                    warn!(args = ?msg.args, "Unexpected OSC /color/green command");
+                    return false;
+                }
+            },
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /color/green command");
+                return false;
+            }
+        },
+        "/color/blue" => match &msg.args[..] {
+            [arg] => match osc_channel(arg, float_mode) {
+                Some(blue) => {
+                    let mut lights = lights.lock().await;
+                    let color = lights.get();
+
+                    lights.set(Color { blue, ..color }, source);
+                }
+                None => {
+                    warn!(args = ?msg.args, "Unexpected OSC /color/blue command");
+                    return false;
+                }
+            },
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /color/blue command");
+                return false;
+            }
+        },
+        // scales the last color that was on, like the brightness of the other interfaces
+        "/brightness" => match &msg.args[..] {
+            [arg] => match osc_channel(arg, float_mode) {
+                Some(0) => {
+                    lights.lock().await.set_pattern(&Pattern::Off, source);
+                }
+                Some(brightness) => {
+                    let mut lights = lights.lock().await;
+                    let (color, _brightness) = lights.get_on_pattern().color().split_brightness();
+
+                    lights.set_pattern(&Pattern::Solid(color.with_brightness(brightness)), source);
+                }
+                None => {
+                    warn!(args = ?msg.args, "Unexpected OSC /brightness command");
+                    return false;
+                }
+            },
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /brightness command");
+                return false;
+            }
+        },
+        "/pattern/off" => match &msg.args[..] {
+            [] => {
+                lights.lock().await.set_pattern(&Pattern::Off, source);
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /pattern/off command");
+                return false;
+            }
+        },
+        "/pattern/solid" => match osc_color(&msg.args, float_mode) {
+            Some((color, [])) => {
+                lights
+                    .lock()
+                    .await
+                    .set_pattern(&Pattern::Solid(color), source);
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /pattern/solid command");
+                return false;
+            }
+        },
+        "/pattern/custom" => match osc_frames(&msg.args, float_mode) {
+            Some(frames) => {
+                lights
+                    .lock()
+                    .await
+                    .set_pattern(&Pattern::Custom(frames), source);
+            }
+            None => {
+                warn!(args = ?msg.args, "Unexpected OSC /pattern/custom command");
+                return false;
+            }
+        },
+        "/pattern/audio" => match &msg.args[..] {
+            [] => {
+                lights
+                    .lock()
+                    .await
+                    .set_pattern(&Pattern::Audio(Audio::new(1.0)), source);
+            }
+            [OscType::Float(gain)] if fooster_lights::valid_gain(*gain as f64) => {
+                lights
+                    .lock()
+                    .await
+                    .set_pattern(&Pattern::Audio(Audio::new(*gain as f64)), source);
+            }
+            [OscType::Double(gain)] if fooster_lights::valid_gain(*gain) => {
+                lights
+                    .lock()
+                    .await
+                    .set_pattern(&Pattern::Audio(Audio::new(*gain)), source);
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /pattern/audio command");
+                return false;
+            }
+        },
+        "/bpm" => match osc_bpm(&msg.args) {
+            Some(bpm) => {
+                lights.lock().await.set_bpm(bpm);
+            }
+            None => {
+                warn!(args = ?msg.args, "Unexpected OSC /bpm command");
+                return false;
+            }
+        },
+        "/tap" => match &msg.args[..] {
+            [] => {
+                lights.lock().await.tap();
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /tap command");
+                return false;
+            }
+        },
+        "/undo" => match &msg.args[..] {
+            [] => {
+                if !lights.lock().await.undo(source) {
+                    warn!("Nothing to undo for OSC /undo command");
+                }
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /undo command");
+                return false;
+            }
+        },
+        "/redo" => match &msg.args[..] {
+            [] => {
+                if !lights.lock().await.redo(source) {
+                    warn!("Nothing to redo for OSC /redo command");
+                }
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /redo command");
+                return false;
+            }
+        },
+        "/pattern" => match &msg.args[..] {
+            [OscType::String(string)] => match serde_json::from_str::<Pattern>(string) {
+                Ok(pattern) => {
+                    lights.lock().await.set_pattern(&pattern, source);
+                }
+                Err(err) => {
+                    warn!(error = %err, "Failed to parse pattern from OSC /pattern command");
+                    return false;
+                }
+            },
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /pattern command");
+                return false;
+            }
+        },
+        "/get/color" => match &msg.args[..] {
+            [] => {
+                let color = lights.lock().await.get();
+
+                osc_send_color(socket, peer, color).await;
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /get/color command");
+                return false;
+            }
+        },
+        "/get/pattern" => match &msg.args[..] {
+            [] => {
+                let pattern = lights.lock().await.get_pattern().clone();
+
+                osc_send_pattern(socket, peer, &pattern).await;
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /get/pattern command");
+                return false;
+            }
+        },
+        "/get/bpm" => match &msg.args[..] {
+            [] => {
+                let bpm = lights.lock().await.get_bpm();
+
+                osc_send(socket, peer, "/bpm", vec![OscType::Float(bpm as f32)]).await;
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /get/bpm command");
+                return false;
+            }
+        },
+        "/subscribe" => match &msg.args[..] {
+            [] => {
+                if !osc_subscribe(subscribers, peer.addr(), peer.clone()) {
+                    warn!("Too many OSC subscribers");
+                    return false;
+                }
+            }
+            [OscType::Int(port)] if (1..=(u16::MAX as i32)).contains(port) => {
+                let addr = SocketAddr::new(peer.addr().ip(), *port as u16);
+
+                if !osc_subscribe(subscribers, addr, OscPeer::Udp(addr)) {
+                    warn!("Too many OSC subscribers");
+                    return false;
+                }
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /subscribe command");
+                return false;
+            }
+        },
+        "/unsubscribe" => match &msg.args[..] {
+            [] => {
+                subscribers.remove(&peer.addr());
+            }
+            [OscType::Int(port)] if (1..=(u16::MAX as i32)).contains(port) => {
+                subscribers.remove(&SocketAddr::new(peer.addr().ip(), *port as u16));
+            }
+            _ => {
+                warn!(args = ?msg.args, "Unexpected OSC /unsubscribe command");
+                return false;
+            }
+        },
+        _ => {
+            warn!(args = ?msg.args, "Unexpected OSC message");
+            return false;
+        }
+    }
+
+    true
+}
+
+fn osc_delay(timetag: OscTime) -> Option<Duration> {
+    // timetags before the unix epoch (including the special immediate timetag) are never delayed
+    if (timetag.seconds as u64) < 2_208_988_800 {
+        return None;
+    }
+
+    SystemTime::from(timetag)
+        .duration_since(SystemTime::now())
+        .ok()
+}
+
+fn osc_unpack(
+    packet: OscPacket,
+    delay: Option<Duration>,
+    messages: &mut Vec<(Option<Duration>, OscMessage)>,
+) {
+    match packet {
+        OscPacket::Message(msg) => {
+            messages.push((delay, msg));
+        }
+        OscPacket::Bundle(bundle) => {
+            // nested bundles must not be scheduled before their enclosing bundle
+            let delay = osc_delay(bundle.timetag).max(delay);
+
+            for packet in bundle.content {
+                osc_unpack(packet, delay, messages);
+            }
+        }
+    }
+}
+
+#[instrument(name = "osc_tcp", skip_all, fields(%peer))]
+async fn osc_tcp_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    packets: mpsc::Sender<(OscPacket, OscPeer)>,
+) {
+    let (mut reader, mut writer) = stream.into_split();
+
+    let (sender, mut replies) = mpsc::unbounded_channel::<Vec<u8>>();
+
+    let replier = tokio::spawn(async move {
+        while let Some(frame) = replies.recv().await {
+            match writer.write_all(&frame).await {
+                Ok(()) => {}
+                Err(err) => {
+                    warn!(%peer, error = %err, "Error sending to OSC connection");
+                    break;
+                }
+            }
+        }
+    });
+
+    let osc_peer = OscPeer::Tcp(peer, sender);
+
+    let mut decoder = SlipDecoder::default();
+
+    let mut buffer = [0u8; 4096];
+
+    'connection: loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => {
+                break;
+            }
+            Ok(size) => {
+                for byte in &buffer[..size] {
+                    if let Some(frame) = decoder.push(*byte) {
+                        match rosc::decoder::decode_udp(&frame) {
+                            Ok((_, packet)) => {
+                                if packets.send((packet, osc_peer.clone())).await.is_err() {
+                                    break 'connection;
+                                }
+                            }
+                            Err(err) => {
+                                warn!(error = ?err, "Error decoding OSC packet");
+                            }
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                warn!(error = %err, "Error receiving from OSC connection");
+                break;
+            }
+        }
+    }
+
+    // closing the replies frees the peer's subscription slot instead of waiting for a failed send
+    replier.abort();
+}
+
+async fn osc_tcp_server(
+    listener: TcpListener,
+    allowlist: Option<Vec<auth::Network>>,
+    packets: mpsc::Sender<(OscPacket, OscPeer)>,
+    metrics: SharedMetrics,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((_stream, peer)) if !auth::allowed(&allowlist, peer.ip()) => {
+                warn!(%peer, "Rejected OSC connection");
+                metrics.osc_denied();
+            }
+            Ok((stream, peer)) => {
+                let packets_conn = packets.clone();
+
+                tokio::spawn(async move {
+                    osc_tcp_connection(stream, peer, packets_conn).await;
+                });
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to accept OSC connection");
+            }
+        }
+    }
+}
+
+pub async fn osc_server(lights: SharedLights, metrics: SharedMetrics) {
+    let address = match env::var("OSC_ADDRESS") {
+        Ok(val) => val,
+        Err(_err) => String::from(if cfg!(debug_assertions) {
+            "127.0.0.1"
+        } else {
+            "0.0.0.0"
+        }),
+    };
+
+    let port: u16 = match env::var("OSC_PORT") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 1337,
+    };
+
+    let float_mode = match env::var("OSC_FLOAT_MODE") {
+        Ok(val) => match val.as_ref() {
+            "normalized" => OscFloatMode::Normalized,
+            "legacy" => OscFloatMode::Legacy,
+            _ => panic!("Unknown OSC float mode: {}", val),
+        },
+        Err(_err) => OscFloatMode::Normalized,
+    };
+
+    let allowlist = auth::allowlist_from_env("OSC_ALLOW");
+
+    let socket = UdpSocket::bind((address.as_ref(), port))
+        .await
+        .expect("Failed to bind UDP OSC address");
+
+    println!(
+        "{}{} {}",
+        Paint::masked("🎛  "),
+        Paint::default("OSC server started on").bold(),
+        Paint::default(socket.local_addr().unwrap())
+            .bold()
+            .underline()
+    );
+
+    // the sender is kept here even without a TCP listener so the channel never closes
+    let (tcp_packets_sender, mut tcp_packets) = mpsc::channel::<(OscPacket, OscPeer)>(64);
+
+    if let Ok(val) = env::var("OSC_TCP_PORT") {
+        let tcp_port: u16 = val.parse().unwrap();
+
+        let listener = TcpListener::bind((address.as_ref(), tcp_port))
+            .await
+            .expect("Failed to bind TCP OSC address");
+
+        println!(
+            "{}{} {}",
+            Paint::masked("🎛  "),
+            Paint::default("OSC server started on").bold(),
+            Paint::default(String::from("tcp://") + &listener.local_addr().unwrap().to_string())
+                .bold()
+                .underline()
+        );
+
+        let allowlist_tcp = allowlist.clone();
+        let packets = tcp_packets_sender.clone();
+        let metrics_tcp = Arc::clone(&metrics);
+
+        tokio::spawn(async move {
+            osc_tcp_server(listener, allowlist_tcp, packets, metrics_tcp).await;
+        });
+    }
+
+    let mut buffer = [0u8; rosc::decoder::MTU];
+
+    let mut queue = BTreeMap::<(Instant, u64), (OscMessage, OscPeer)>::new();
+    let mut sequence: u64 = 0;
+
+    let mut subscribers = OscSubscribers::new();
+
+    let mut changes = lights.lock().await.subscribe();
+
+    loop {
+        let next = queue.keys().next().map(|(instant, _)| *instant);
+
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                match received {
+                    Ok((_size, addr)) if !auth::allowed(&allowlist, addr.ip()) => {
+                        warn!(source = %addr, "Rejected OSC packet");
+                        metrics.osc_denied();
+                        None
+                    }
+                    Ok((size, addr)) => match rosc::decoder::decode_udp(&buffer[..size]) {
+                        Ok((_, packet)) => Some((packet, OscPeer::Udp(addr))),
+                        Err(err) => {
+                            warn!(source = %addr, error = ?err, "Error decoding OSC packet");
+                            None
+                        }
+                    },
+                    Err(err) => {
+                        warn!(error = %err, "Error receiving from OSC socket");
+                        None
+                    }
+                }
+            }
+
+            received = tcp_packets.recv() => received,
+
+            _ = time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                while let Some(entry) = queue.first_entry() {
+                    if entry.key().0 > Instant::now() {
+                        break;
+                    }
+
+                    let (msg, peer) = entry.remove();
+
+                    osc_message(&msg, &peer, &socket, &mut subscribers, float_mode, &lights, &metrics).await;
+                }
+
+                None
+            }
+
+            change = changes.recv() => {
+                let (color, pattern) = match change {
+                    Ok(Change::Pattern { pattern, color }) => (color, Some(pattern)),
+                    Ok(Change::Frame { color }) => (color, None),
+                    Err(RecvError::Lagged(_skipped)) => {
+                        // too far behind to replay changes so resynchronize with the current state
+                        let lights = lights.lock().await;
+
+                        (lights.get(), Some(lights.get_pattern().clone()))
+                    },
+                    Err(RecvError::Closed) => {
+                        break;
+                    },
+                };
+
+                subscribers.retain(|_, subscriber| !subscriber.is_expired());
+
+                for subscriber in subscribers.values() {
+                    if let Some(pattern) = &pattern {
+                        osc_send_pattern(&socket, &subscriber.peer, pattern).await;
+                    }
+
+                    osc_send_color(&socket, &subscriber.peer, color).await;
+                }
+
+                None
+            }
+        };
+
+        if let Some((packet, peer)) = received {
+            let mut messages = Vec::new();
+
+            osc_unpack(packet, None, &mut messages);
+
+            for (delay, msg) in messages {
+                match delay {
+                    Some(delay) if delay > OSC_SCHEDULE_HORIZON => {
+                        warn!(
+                            source = %peer.addr(),
+                            address = msg.addr,
+                            delay = ?delay,
+                            "Dropping OSC message scheduled too far ahead"
+                        );
+                    }
+                    Some(_delay) if queue.len() >= OSC_SCHEDULED_MAX => {
+                        warn!(
+                            source = %peer.addr(),
+                            address = msg.addr,
+                            "Dropping OSC message with too many already scheduled"
+                        );
+                    }
+                    Some(delay) => {
+                        // sequence keeps messages for the same time in the order they arrived
+                        queue.insert((Instant::now() + delay, sequence), (msg, peer.clone()));
+                        sequence = sequence.wrapping_add(1);
+                    }
+                    None => {
+                        osc_message(
+                            &msg,
+                            &peer,
+                            &socket,
+                            &mut subscribers,
+                            float_mode,
+                            &lights,
+                            &metrics,
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rosc::OscColor;
+
+    fn slip_decode(decoder: &mut SlipDecoder, bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes
+            .iter()
+            .filter_map(|byte| decoder.push(*byte))
+            .collect()
+    }
+
+    #[test]
+    fn slip_encode_escapes() {
+        assert_eq!(
+            slip_encode(&[1, SLIP_END, 2, SLIP_ESC, 3]),
+            [
+                SLIP_END,
+                1,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                2,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                3,
+                SLIP_END
+            ]
+        );
+        assert_eq!(slip_encode(&[]), [SLIP_END, SLIP_END]);
+    }
+
+    #[test]
+    fn slip_round_trip() {
+        let packet = [0, SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC, 255];
+
+        assert_eq!(
+            slip_decode(&mut SlipDecoder::default(), &slip_encode(&packet)),
+            [packet.to_vec()]
+        );
+    }
+
+    #[test]
+    fn slip_decode_frames() {
+        let mut decoder = SlipDecoder::default();
+
+        // frames delimited at the end only, at both ends, and split across reads
+        let mut frames = slip_decode(&mut decoder, &[1, 2, SLIP_END, SLIP_END, 3, SLIP_END, 4]);
+        frames.extend(slip_decode(&mut decoder, &[5, SLIP_END]));
+
+        assert_eq!(frames, [vec![1, 2], vec![3], vec![4, 5]]);
+    }
+
+    #[test]
+    fn slip_decode_escape_across_reads() {
+        let mut decoder = SlipDecoder::default();
+
+        assert!(slip_decode(&mut decoder, &[1, SLIP_ESC]).is_empty());
+        assert_eq!(
+            slip_decode(&mut decoder, &[SLIP_ESC_END, SLIP_END]),
+            [vec![1, SLIP_END]]
+        );
+    }
+
+    #[test]
+    fn slip_decode_overflow() {
+        let mut decoder = SlipDecoder::default();
+
+        let mut bytes = vec![1; OSC_TCP_MAX_PACKET + 1];
+        bytes.push(SLIP_END);
+
+        // the oversized frame is dropped and the next one is unaffected
+        assert!(slip_decode(&mut decoder, &bytes).is_empty());
+        assert_eq!(slip_decode(&mut decoder, &[2, SLIP_END]), [vec![2]]);
+
+        // a frame of exactly the limit still fits
+        let mut bytes = vec![3; OSC_TCP_MAX_PACKET];
+        bytes.push(SLIP_END);
+
+        assert_eq!(
+            slip_decode(&mut decoder, &bytes),
+            [vec![3; OSC_TCP_MAX_PACKET]]
+        );
+    }
+
+    fn rgb(args: &[OscType], float_mode: OscFloatMode) -> Option<(u8, u8, u8)> {
+        osc_color(args, float_mode).map(|(color, _rest)| (color.red, color.green, color.blue))
+    }
+
+    #[test]
+    fn osc_channel_normalized_floats() {
+        let mode = OscFloatMode::Normalized;
+
+        assert_eq!(osc_channel(&OscType::Float(0.0), mode), Some(0));
+        assert_eq!(osc_channel(&OscType::Float(0.5), mode), Some(128));
+        assert_eq!(osc_channel(&OscType::Float(1.0), mode), Some(255));
+        assert_eq!(osc_channel(&OscType::Double(1.0), mode), Some(255));
+
+        // out of range floats saturate instead of wrapping
+        assert_eq!(osc_channel(&OscType::Float(128.0), mode), Some(255));
+        assert_eq!(osc_channel(&OscType::Float(-0.5), mode), Some(0));
+    }
+
+    #[test]
+    fn osc_channel_legacy_floats() {
+        let mode = OscFloatMode::Legacy;
+
+        assert_eq!(osc_channel(&OscType::Float(0.0), mode), Some(0));
+        assert_eq!(osc_channel(&OscType::Float(1.0), mode), Some(1));
+        assert_eq!(osc_channel(&OscType::Float(128.0), mode), Some(128));
+        assert_eq!(osc_channel(&OscType::Double(255.0), mode), Some(255));
+        assert_eq!(osc_channel(&OscType::Float(300.0), mode), Some(255));
+    }
+
+    #[test]
+    fn osc_channel_ints() {
+        for mode in [OscFloatMode::Normalized, OscFloatMode::Legacy] {
+            assert_eq!(osc_channel(&OscType::Int(1), mode), Some(1));
+            assert_eq!(osc_channel(&OscType::Int(255), mode), Some(255));
+            assert_eq!(osc_channel(&OscType::Int(256), mode), Some(255));
+            assert_eq!(osc_channel(&OscType::Int(-1), mode), Some(0));
+            assert_eq!(osc_channel(&OscType::Long(i64::MAX), mode), Some(255));
+            assert_eq!(osc_channel(&OscType::Long(i64::MIN), mode), Some(0));
+            assert_eq!(osc_channel(&OscType::String("1".into()), mode), None);
+        }
+    }
+
+    #[test]
+    fn osc_channel_non_finite_floats() {
+        for mode in [OscFloatMode::Normalized, OscFloatMode::Legacy] {
+            assert_eq!(osc_channel(&OscType::Float(f32::NAN), mode), Some(0));
+            assert_eq!(osc_channel(&OscType::Float(f32::INFINITY), mode), Some(255));
+            assert_eq!(
+                osc_channel(&OscType::Double(f64::NEG_INFINITY), mode),
+                Some(0)
+            );
+        }
+    }
+
+    #[test]
+    fn osc_color_mixed_args() {
+        let args = [
+            OscType::Int(255),
+            OscType::Float(0.5),
+            OscType::Double(0.0),
+            OscType::Int(100),
+        ];
+
+        let (color, rest) = osc_color(&args, OscFloatMode::Normalized).unwrap();
+
+        assert_eq!((color.red, color.green, color.blue), (255, 128, 0));
+        assert_eq!(rest, &[OscType::Int(100)]);
+
+        assert_eq!(rgb(&args, OscFloatMode::Legacy), Some((255, 1, 0)));
+    }
+
+    #[test]
+    fn osc_color_rejects_bad_args() {
+        let mode = OscFloatMode::Normalized;
+
+        assert_eq!(rgb(&[], mode), None);
+        assert_eq!(rgb(&[OscType::Int(1), OscType::Int(2)], mode), None);
+        assert_eq!(
+            rgb(
+                &[OscType::Int(1), OscType::Bool(true), OscType::Int(3)],
+                mode
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn osc_color_rgba() {
+        let color = OscColor {
+            red: 1,
+            green: 2,
+            blue: 3,
+            alpha: 4,
+        };
+
+        assert_eq!(
+            rgb(&[OscType::Color(color)], OscFloatMode::Normalized),
+            Some((1, 2, 3))
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+
+use rocket::tokio;
+use rocket::tokio::task::AbortHandle;
+use rocket::tokio::time::{Duration, Instant};
+
+use tracing::error;
+
+use crate::auth::{Authorized, ReadOnly};
+use crate::metrics::SharedMetrics;
+use crate::SharedLights;
+
+// the pattern output summarizes its timing every second, so it is stuck if it has not for a while
+const PATTERN_STALL: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Serialize)]
+#[serde(
+    crate = "rocket::serde",
+    rename_all = "lowercase",
+    tag = "type",
+    content = "content"
+)]
+pub enum TaskState {
+    Running,
+    // still running but not making progress
+    Stalled,
+    // returned, which none of the servers are supposed to do
+    Exited,
+    // reached the end of a finite input, like an audio file
+    Finished,
+    // stopped on an error it reported
+    Failed(String),
+    Panicked(String),
+    // aborted during shutdown
+    Stopped,
+}
+
+pub struct Tasks {
+    started: Instant,
+    states: Mutex<BTreeMap<&'static str, TaskState>>,
+    handles: Mutex<Vec<AbortHandle>>,
+}
+
+pub type SharedTasks = Arc<Tasks>;
+
+impl Tasks {
+    pub fn new() -> Tasks {
+        Tasks {
+            started: Instant::now(),
+            states: Mutex::new(BTreeMap::new()),
+            handles: Mutex::new(Vec::new()),
+        }
+    }
+
+    // spawns a long-running server task and watches it so its death shows up in the status
+    pub fn spawn<F>(self: &Arc<Self>, name: &'static str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.supervise(name, async move {
+            future.await;
+
+            error!(task = name, "Task exited");
+
+            TaskState::Exited
+        });
+    }
+
+    // spawns a task that may end on its own without being unhealthy, unless it fails
+    pub fn spawn_finite<F, E>(self: &Arc<Self>, name: &'static str, future: F)
+    where
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display,
+    {
+        self.supervise(name, async move {
+            match future.await {
+                Ok(()) => TaskState::Finished,
+                Err(err) => {
+                    error!(task = name, error = %err, "Task failed");
+
+                    TaskState::Failed(err.to_string())
+                }
+            }
+        });
+    }
+
+    // the future resolves to the state to record when it returns
+    fn supervise<F>(self: &Arc<Self>, name: &'static str, future: F)
+    where
+        F: Future<Output = TaskState> + Send + 'static,
+    {
+        self.set(name, TaskState::Running);
+
+        let handle = tokio::spawn(future);
+        let tasks = Arc::clone(self);
+
+        self.handles.lock().unwrap().push(handle.abort_handle());
+
+        tokio::spawn(async move {
+            let state = match handle.await {
+                Ok(state) => state,
+                Err(err) if err.is_panic() => {
+                    let message = panic_message(err.into_panic());
+
+                    error!(task = name, %message, "Task panicked");
+
+                    TaskState::Panicked(message)
+                }
+                Err(err) if err.is_cancelled() => TaskState::Stopped,
+                Err(err) => {
+                    error!(task = name, error = %err, "Task failed");
+
+                    TaskState::Panicked(err.to_string())
+                }
+            };
+
+            tasks.set(name, state);
+        });
+    }
+
+    pub fn stop(&self) {
+        for handle in self.handles.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+    }
+
+    fn set(&self, name: &'static str, state: TaskState) {
+        self.states.lock().unwrap().insert(name, state);
+    }
+
+    fn states(&self, metrics: &SharedMetrics) -> BTreeMap<&'static str, TaskState> {
+        let mut states = self.states.lock().unwrap().clone();
+
+        if let Some(state @ TaskState::Running) = states.get_mut("pattern") {
+            let updated = metrics.window().map(|window| window.updated);
+
+            if updated.unwrap_or(self.started).elapsed() > PATTERN_STALL {
+                *state = TaskState::Stalled;
+            }
+        }
+
+        states
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => String::from(*message),
+            Err(_payload) => String::from("unknown panic"),
+        },
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Health {
+    healthy: bool,
+    tasks: BTreeMap<&'static str, TaskState>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct OutputStatus {
+    backend: &'static str,
+    last_error: Option<OutputErrorStatus>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct OutputErrorStatus {
+    message: String,
+    channel: &'static str,
+    // milliseconds since the error, matching the other durations in the API
+    age: u128,
+    // consecutive failed writes, zero once the output has recovered
+    failures: u32,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TickStatus {
+    fps: Option<f64>,
+    // milliseconds frames started late by over the last second
+    jitter_mean: Option<f64>,
+    jitter_max: Option<f64>,
+    overruns: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StatusReport {
+    version: &'static str,
+    // milliseconds since startup
+    uptime: u128,
+    output: OutputStatus,
+    tasks: BTreeMap<&'static str, TaskState>,
+    tick: TickStatus,
+}
+
+// unauthenticated so load balancers and probes can use it, and only says which tasks are down
+#[get("/health")]
+pub async fn health(
+    tasks: &State<SharedTasks>,
+    metrics: &State<SharedMetrics>,
+) -> (Status, Json<Health>) {
+    let tasks = tasks.states(metrics);
+
+    let healthy = tasks
+        .values()
+        .all(|state| matches!(state, TaskState::Running | TaskState::Finished));
+
+    let status = if healthy {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (status, Json(Health { healthy, tasks }))
+}
+
+#[get("/status")]
+pub async fn status(
+    _auth: Authorized<ReadOnly>,
+    tasks: &State<SharedTasks>,
+    metrics: &State<SharedMetrics>,
+    lights: &State<SharedLights>,
+) -> Json<StatusReport> {
+    let output = {
+        let lights = lights.lock().await;
+
+        OutputStatus {
+            backend: lights.get_output_backend(),
+            last_error: lights
+                .get_output_failure()
+                .map(|failure| OutputErrorStatus {
+                    message: failure.error.to_string(),
+                    channel: failure.error.channel,
+                    age: failure.at.elapsed().as_millis(),
+                    failures: failure.failures,
+                }),
+        }
+    };
+
+    let window = metrics.window();
+
+    Json(StatusReport {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: tasks.started.elapsed().as_millis(),
+        output,
+        tasks: tasks.states(metrics),
+        tick: TickStatus {
+            fps: window.map(|window| window.fps),
+            jitter_mean: window.map(|window| window.jitter_mean.as_secs_f64() * 1000.0),
+            jitter_max: window.map(|window| window.jitter_max.as_secs_f64() * 1000.0),
+            overruns: metrics.overruns(),
+        },
+    })
+}
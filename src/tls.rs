@@ -0,0 +1,61 @@
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use rocket::figment::Figment;
+
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+// TLS is off unless both a certificate chain and a private key are configured
+fn tls_paths() -> Option<(String, String)> {
+    match (env::var("TLS_CERT"), env::var("TLS_KEY")) {
+        (Ok(certs), Ok(key)) => Some((certs, key)),
+        (Ok(_certs), Err(_err)) => panic!("Missing TLS key"),
+        (Err(_err), Ok(_key)) => panic!("Missing TLS certificate"),
+        (Err(_), Err(_)) => None,
+    }
+}
+
+// the same certificate serves https and the standalone wss listener
+pub fn tls_figment(figment: Figment) -> Figment {
+    match tls_paths() {
+        Some((certs, key)) => figment.merge(("tls.certs", certs)).merge(("tls.key", key)),
+        None => figment,
+    }
+}
+
+pub fn tls_acceptor() -> Option<TlsAcceptor> {
+    let (certs_path, key_path) = tls_paths()?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(certs_path).expect("Failed to open TLS certificate file"),
+    ))
+    .expect("Failed to parse TLS certificate file")
+    .into_iter()
+    .map(Certificate)
+    .collect();
+
+    // the same keys rocket accepts, in whichever format comes first
+    let key = rustls_pemfile::read_all(&mut BufReader::new(
+        File::open(key_path).expect("Failed to open TLS key file"),
+    ))
+    .expect("Failed to parse TLS key file")
+    .into_iter()
+    .find_map(|item| match item {
+        rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+        _ => None,
+    })
+    .expect("Missing private key in TLS key file");
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .expect("Invalid TLS certificate or key");
+
+    Some(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,471 @@
+use std::env;
+use std::net::SocketAddr;
+
+use rocket::serde::json::serde_json::{json, Value};
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::State;
+
+use rocket::tokio::net::UdpSocket;
+use rocket::tokio::time::Duration;
+
+use tracing::{info, instrument, warn};
+
+use yansi::Paint;
+
+use crate::auth;
+use crate::auth::{Admin, Authorized, ReadOnly};
+use crate::history::Source;
+use crate::{Audio, Color, Lights, Pattern, SharedLights};
+
+const WLED_VERSION: &str = "0.14.0";
+const WLED_EFFECTS: &[&str] = &["Solid", "Custom", "Audio"];
+const WLED_PALETTES: &[&str] = &["Default"];
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum WledOn {
+    Set(bool),
+    Toggle(String),
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum WledColor {
+    Channels(Vec<u8>),
+    Hex(String),
+}
+
+impl WledColor {
+    fn color(&self) -> Option<Color> {
+        match self {
+            WledColor::Channels(channels) => match channels[..] {
+                [red, green, blue, ..] => Some(Color { red, green, blue }),
+                _ => None,
+            },
+            WledColor::Hex(hex) => format!("#{}", hex.get(..6)?).parse().ok(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct WledSegment {
+    id: Option<u32>,
+    col: Option<Vec<WledColor>>,
+    fx: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum WledSegments {
+    One(WledSegment),
+    Many(Vec<WledSegment>),
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WledUpdate {
+    on: Option<WledOn>,
+    bri: Option<u8>,
+    seg: Option<WledSegments>,
+    live: Option<bool>,
+    v: Option<bool>,
+}
+
+fn wled_udp_port() -> u16 {
+    match env::var("WLED_UDP_PORT") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 0,
+    }
+}
+
+fn wled_state(lights: &Lights) -> Value {
+    let on = !matches!(lights.get_pattern(), Pattern::Off);
+
+    // wled keeps the color and effect while off, so report the pattern that turning on restores
+    let pattern = lights.get_on_pattern();
+
+    let (color, brightness) = pattern.color().split_brightness();
+
+    let fx = match pattern {
+        Pattern::Custom(_frames) => 1,
+        Pattern::Audio(_audio) => 2,
+        _ => 0,
+    };
+
+    json!({
+        "on": on,
+        "bri": brightness,
+        "transition": 0,
+        "ps": -1,
+        "pl": -1,
+        "lor": 0,
+        "mainseg": 0,
+        "seg": [{
+            "id": 0,
+            "start": 0,
+            "stop": 1,
+            "len": 1,
+            "grp": 1,
+            "spc": 0,
+            "of": 0,
+            "on": on,
+            "frz": false,
+            "bri": 255,
+            "col": [[color.red, color.green, color.blue], [0, 0, 0], [0, 0, 0]],
+            "fx": fx,
+            "sx": 128,
+            "ix": 128,
+            "pal": 0,
+            "sel": true,
+            "rev": false,
+            "mi": false,
+        }],
+    })
+}
+
+fn wled_info(lights: &Lights) -> Value {
+    json!({
+        "ver": WLED_VERSION,
+        "vid": 0,
+        "leds": {
+            "count": 1,
+            "rgbw": false,
+            "wv": false,
+            "cct": false,
+            "pwr": 0,
+            "maxpwr": 0,
+            "maxseg": 1,
+            "seglc": [1],
+            "lc": 1,
+        },
+        "str": false,
+        "name": "Lights",
+        "udpport": wled_udp_port(),
+        "live": lights.get_realtime(),
+        "lm": "",
+        "lip": "",
+        "ws": -1,
+        "fxcount": WLED_EFFECTS.len(),
+        "palcount": WLED_PALETTES.len(),
+        "arch": env!("CARGO_PKG_NAME"),
+        "core": env!("CARGO_PKG_VERSION"),
+        "brand": "WLED",
+        "product": "Lights",
+        "mac": "",
+        "ip": "",
+    })
+}
+
+#[instrument(name = "wled", skip_all, fields(%source))]
+fn wled_update(update: &WledUpdate, lights: &mut Lights, source: Source) {
+    if update.live == Some(false) {
+        lights.clear_realtime(source);
+    }
+
+    let current = lights.get_pattern().clone();
+
+    let mut on = !matches!(current, Pattern::Off);
+    let mut pattern = lights.get_on_pattern().clone();
+
+    match &update.on {
+        Some(WledOn::Set(val)) => {
+            on = *val;
+        }
+        Some(WledOn::Toggle(val)) if val == "t" => {
+            on = !on;
+        }
+        Some(WledOn::Toggle(val)) => {
+            warn!(value = val, "Unexpected WLED on value");
+        }
+        None => {}
+    }
+
+    // only a single segment spanning the whole strip exists
+    let segment = match &update.seg {
+        Some(WledSegments::One(segment)) => Some(segment),
+        Some(WledSegments::Many(segments)) => {
+            segments.iter().find(|segment| segment.id.unwrap_or(0) == 0)
+        }
+        None => None,
+    };
+
+    let color = segment
+        .and_then(|segment| segment.col.as_ref())
+        .and_then(|colors| colors.first())
+        .and_then(|color| color.color());
+
+    match segment.and_then(|segment| segment.fx) {
+        Some(0) => {
+            pattern = Pattern::Solid(pattern.color());
+        }
+        Some(2) if !matches!(pattern, Pattern::Audio(_)) => {
+            pattern = Pattern::Audio(Audio::new(1.0));
+        }
+        Some(1 | 2) => {
+            // the running pattern is kept since custom patterns cannot be created through wled
+        }
+        Some(fx) => {
+            warn!(effect = fx, "Unexpected WLED effect");
+        }
+        None => {}
+    }
+
+    if color.is_some() || update.bri.is_some() {
+        let (full, brightness) = pattern.color().split_brightness();
+
+        let brightness = match update.bri {
+            Some(0) => {
+                // wled turns off instead of dimming to black
+                on = false;
+                brightness
+            }
+            Some(brightness) => brightness,
+            None if brightness == 0 => 255,
+            None => brightness,
+        };
+
+        pattern = Pattern::Solid(color.unwrap_or(full).with_brightness(brightness));
+    }
+
+    let pattern = if on { pattern } else { Pattern::Off };
+
+    if pattern != current {
+        info!(pattern = pattern.name(), "Set pattern");
+        lights.set_pattern(&pattern, source);
+    }
+}
+
+#[get("/json")]
+pub async fn wled_get(_auth: Authorized<ReadOnly>, lights: &State<SharedLights>) -> Json<Value> {
+    let lights = lights.lock().await;
+
+    Json(json!({
+        "state": wled_state(&lights),
+        "info": wled_info(&lights),
+        "effects": WLED_EFFECTS,
+        "palettes": WLED_PALETTES,
+    }))
+}
+
+#[post("/json", data = "<update>")]
+pub async fn wled_post(
+    auth: Authorized<Admin>,
+    peer: SocketAddr,
+    update: Json<WledUpdate>,
+    lights: &State<SharedLights>,
+) -> Json<Value> {
+    wled_set_state(auth, peer, update, lights).await
+}
+
+#[get("/json/state")]
+pub async fn wled_get_state(
+    _auth: Authorized<ReadOnly>,
+    lights: &State<SharedLights>,
+) -> Json<Value> {
+    Json(wled_state(&*lights.lock().await))
+}
+
+#[post("/json/state", data = "<update>")]
+pub async fn wled_set_state(
+    _auth: Authorized<Admin>,
+    peer: SocketAddr,
+    update: Json<WledUpdate>,
+    lights: &State<SharedLights>,
+) -> Json<Value> {
+    let mut lights = lights.lock().await;
+
+    wled_update(&update, &mut lights, Source::Wled(peer));
+
+    if update.v == Some(true) {
+        Json(wled_state(&lights))
+    } else {
+        Json(json!({ "success": true }))
+    }
+}
+
+#[get("/json/info")]
+pub async fn wled_get_info(
+    _auth: Authorized<ReadOnly>,
+    lights: &State<SharedLights>,
+) -> Json<Value> {
+    Json(wled_info(&*lights.lock().await))
+}
+
+#[get("/json/effects")]
+pub async fn wled_get_effects() -> Json<&'static [&'static str]> {
+    Json(WLED_EFFECTS)
+}
+
+#[get("/json/palettes")]
+pub async fn wled_get_palettes() -> Json<&'static [&'static str]> {
+    Json(WLED_PALETTES)
+}
+
+fn wled_realtime(packet: &[u8]) -> Option<(Color, Option<Duration>)> {
+    let (color, timeout) = match packet {
+        // WARLS: index, red, green, blue for each changed led
+        [1, timeout, data @ ..] => (
+            data.chunks_exact(4)
+                .find(|led| led[0] == 0)
+                .map(|led| Color {
+                    red: led[1],
+                    green: led[2],
+                    blue: led[3],
+                })?,
+            *timeout,
+        ),
+        // DRGB: red, green, blue for each led
+        [2, timeout, red, green, blue, ..] => (
+            Color {
+                red: *red,
+                green: *green,
+                blue: *blue,
+            },
+            *timeout,
+        ),
+        // DRGBW: red, green, blue, white for each led
+        [3, timeout, red, green, blue, white, ..] => (
+            Color {
+                red: red.saturating_add(*white),
+                green: green.saturating_add(*white),
+                blue: blue.saturating_add(*white),
+            },
+            *timeout,
+        ),
+        // DNRGB: start index then red, green, blue for each led
+        [4, timeout, 0, 0, red, green, blue, ..] => (
+            Color {
+                red: *red,
+                green: *green,
+                blue: *blue,
+            },
+            *timeout,
+        ),
+        _ => {
+            return None;
+        }
+    };
+
+    let timeout = match timeout {
+        255 => None,
+        seconds => Some(Duration::from_secs(seconds as u64)),
+    };
+
+    Some((color, timeout))
+}
+
+pub async fn wled_realtime_server(lights: SharedLights) {
+    let address = match env::var("WLED_ADDRESS") {
+        Ok(val) => val,
+        Err(_err) => String::from(if cfg!(debug_assertions) {
+            "127.0.0.1"
+        } else {
+            "0.0.0.0"
+        }),
+    };
+
+    let allowlist = auth::allowlist_from_env("WLED_ALLOW");
+
+    let socket = UdpSocket::bind((address, wled_udp_port()))
+        .await
+        .expect("Failed to bind UDP WLED address");
+
+    println!(
+        "{}{} {}",
+        Paint::masked("🌈 "),
+        Paint::default("WLED realtime server started on").bold(),
+        Paint::default(socket.local_addr().unwrap())
+            .bold()
+            .underline()
+    );
+
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((_size, addr)) if !auth::allowed(&allowlist, addr.ip()) => {
+                warn!(source = %addr, "Rejected WLED realtime packet");
+            }
+            Ok((size, addr)) => match wled_realtime(&buffer[..size]) {
+                Some((color, timeout)) => {
+                    lights
+                        .lock()
+                        .await
+                        .set_realtime(color, None, timeout, Source::Wled(addr));
+                }
+                None => {
+                    warn!(source = %addr, packet = ?&buffer[..size], "Unexpected WLED realtime packet");
+                }
+            },
+            Err(err) => {
+                warn!(error = %err, "Error receiving from WLED socket");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn realtime(packet: &[u8]) -> Option<((u8, u8, u8), Option<Duration>)> {
+        wled_realtime(packet)
+            .map(|(color, timeout)| ((color.red, color.green, color.blue), timeout))
+    }
+
+    #[test]
+    fn realtime_warls() {
+        // only the first led is used, wherever it is in the packet
+        assert_eq!(
+            realtime(&[1, 2, 5, 9, 9, 9, 0, 255, 128, 0]),
+            Some(((255, 128, 0), Some(Duration::from_secs(2))))
+        );
+        assert_eq!(realtime(&[1, 2, 5, 9, 9, 9]), None);
+        assert_eq!(realtime(&[1, 2, 0, 255, 128]), None);
+    }
+
+    #[test]
+    fn realtime_drgb() {
+        assert_eq!(
+            realtime(&[2, 1, 255, 128, 0, 1, 2, 3]),
+            Some(((255, 128, 0), Some(Duration::from_secs(1))))
+        );
+        assert_eq!(realtime(&[2, 1, 255, 128]), None);
+    }
+
+    #[test]
+    fn realtime_drgbw() {
+        // white is added to every channel
+        assert_eq!(
+            realtime(&[3, 1, 255, 128, 0, 64]),
+            Some(((255, 192, 64), Some(Duration::from_secs(1))))
+        );
+    }
+
+    #[test]
+    fn realtime_dnrgb() {
+        assert_eq!(
+            realtime(&[4, 1, 0, 0, 255, 128, 0]),
+            Some(((255, 128, 0), Some(Duration::from_secs(1))))
+        );
+        // updates starting past the first led leave nothing to show
+        assert_eq!(realtime(&[4, 1, 0, 1, 255, 128, 0]), None);
+    }
+
+    #[test]
+    fn realtime_timeout() {
+        assert_eq!(
+            realtime(&[2, 255, 255, 128, 0]),
+            Some(((255, 128, 0), None))
+        );
+    }
+
+    #[test]
+    fn realtime_unknown() {
+        assert_eq!(realtime(&[]), None);
+        assert_eq!(realtime(&[0, 1, 255, 128, 0]), None);
+        assert_eq!(realtime(&[5, 1, 255, 128, 0]), None);
+    }
+}
@@ -0,0 +1,539 @@
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+
+use rocket::futures::sink::{Sink, SinkExt};
+use rocket::futures::stream::{Stream, StreamExt};
+
+use rocket::serde::json::serde_json;
+use rocket::serde::{Deserialize, Serialize};
+
+use rocket::tokio;
+use rocket::tokio::io::{AsyncRead, AsyncWrite};
+use rocket::tokio::net::TcpListener;
+use rocket::tokio::sync::broadcast::error::RecvError;
+
+use rocket_ws::{Channel, WebSocket};
+
+use tokio_tungstenite::tungstenite::error::ProtocolError as WSProtocolError;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse as WSErrorResponse, Request as WSHandshakeRequest,
+    Response as WSHandshakeResponse,
+};
+use tokio_tungstenite::tungstenite::{Error as WSError, Message as WSMessage};
+
+use tracing::{info, instrument, warn};
+
+use yansi::Paint;
+
+use crate::auth::{Authorized, ReadOnly, Scope, Tokens};
+use crate::history::Source;
+use crate::metrics::SharedMetrics;
+use crate::{auth, tls};
+use crate::{Change, Color, Pattern, SharedLights};
+
+pub struct WSInfo(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WSInfo {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Ok(val) = env::var("WS_INFO") {
+            return Outcome::Success(WSInfo(val));
+        }
+
+        // assume secure WebSockets when served over TLS, directly or by a terminating proxy
+        let scheme = match request.headers().get_one("X-Forwarded-Proto") {
+            Some("https") => "wss",
+            _ if request.rocket().config().tls_enabled() => "wss",
+            _ => "ws",
+        };
+
+        match request.host() {
+            Some(host) => {
+                Outcome::Success(WSInfo(format!("{}://{}{}", scheme, host, uri!(websocket))))
+            }
+            None => Outcome::Success(WSInfo(String::new())),
+        }
+    }
+}
+
+#[get("/wsinfo")]
+pub async fn ws_info(info: WSInfo) -> String {
+    info.0
+}
+
+#[get("/ws")]
+pub fn websocket(
+    ws: WebSocket,
+    peer: SocketAddr,
+    auth: Authorized<ReadOnly>,
+    lights: &State<SharedLights>,
+    metrics: &State<SharedMetrics>,
+) -> Channel<'static> {
+    let lights = Arc::clone(lights);
+    let metrics = Arc::clone(metrics);
+
+    ws.channel(move |stream| {
+        Box::pin(async move {
+            ws_connection(stream, peer, auth.scope, lights, metrics).await;
+
+            Ok(())
+        })
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+enum WSTopic {
+    Color,
+    Pattern,
+}
+
+#[derive(Deserialize)]
+#[serde(
+    crate = "rocket::serde",
+    rename_all = "snake_case",
+    tag = "type",
+    content = "content"
+)]
+enum WSCommand {
+    GetColor,
+    SetColor(Color),
+    GetPattern,
+    SetPattern(Pattern),
+    Undo,
+    Redo,
+    Subscribe(Vec<WSTopic>),
+    Unsubscribe(Vec<WSTopic>),
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct WSRequest {
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    command: WSCommand,
+}
+
+#[derive(Serialize)]
+#[serde(
+    crate = "rocket::serde",
+    rename_all = "snake_case",
+    tag = "type",
+    content = "content"
+)]
+enum WSReply {
+    Ack,
+    Error(String),
+    Color(Color),
+    Pattern(Pattern),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct WSResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    reply: WSReply,
+}
+
+struct WSSubscriptions {
+    // clients that never subscribe get bare colors pushed for compatibility
+    topics: Option<Vec<WSTopic>>,
+}
+
+impl WSSubscriptions {
+    fn subscribe(&mut self, topics: &[WSTopic]) {
+        let subscribed = self.topics.get_or_insert_with(Vec::new);
+
+        for topic in topics {
+            if !subscribed.contains(topic) {
+                subscribed.push(*topic);
+            }
+        }
+    }
+
+    fn unsubscribe(&mut self, topics: &[WSTopic]) {
+        let subscribed = self.topics.get_or_insert_with(Vec::new);
+
+        subscribed.retain(|topic| !topics.contains(topic));
+    }
+
+    fn messages(&self, color: Option<Color>, pattern: Option<&Pattern>) -> Vec<String> {
+        match &self.topics {
+            None => color
+                .map(|color| serde_json::to_string(&color).unwrap())
+                .into_iter()
+                .collect(),
+            Some(topics) => {
+                let mut messages = Vec::new();
+
+                if let Some(color) = color {
+                    if topics.contains(&WSTopic::Color) {
+                        messages.push(
+                            serde_json::to_string(&WSResponse {
+                                id: None,
+                                reply: WSReply::Color(color),
+                            })
+                            .unwrap(),
+                        );
+                    }
+                }
+
+                if let Some(pattern) = pattern {
+                    if topics.contains(&WSTopic::Pattern) {
+                        messages.push(
+                            serde_json::to_string(&WSResponse {
+                                id: None,
+                                reply: WSReply::Pattern(pattern.clone()),
+                            })
+                            .unwrap(),
+                        );
+                    }
+                }
+
+                messages
+            }
+        }
+    }
+}
+
+async fn ws_command(
+    command: WSCommand,
+    peer: SocketAddr,
+    scope: Scope,
+    lights: &SharedLights,
+    subscriptions: &mut WSSubscriptions,
+) -> WSReply {
+    match command {
+        WSCommand::SetColor(_color) if scope < Scope::Color => {
+            WSReply::Error(String::from("Token not allowed to set colors"))
+        }
+        WSCommand::SetPattern(_pattern) if scope < Scope::Admin => {
+            WSReply::Error(String::from("Token not allowed to set patterns"))
+        }
+        WSCommand::Undo | WSCommand::Redo if scope < Scope::Admin => {
+            WSReply::Error(String::from("Token not allowed to undo changes"))
+        }
+        WSCommand::GetColor => WSReply::Color(lights.lock().await.get()),
+        WSCommand::SetColor(color) => {
+            info!(%color, "Set color");
+
+            lights.lock().await.set(color, Source::WebSocket(peer));
+
+            WSReply::Ack
+        }
+        WSCommand::GetPattern => WSReply::Pattern(lights.lock().await.get_pattern().clone()),
+        WSCommand::SetPattern(pattern) => {
+            info!(pattern = pattern.name(), "Set pattern");
+
+            lights
+                .lock()
+                .await
+                .set_pattern(&pattern, Source::WebSocket(peer));
+
+            WSReply::Ack
+        }
+        WSCommand::Undo => {
+            if lights.lock().await.undo(Source::WebSocket(peer)) {
+                info!("Undid change");
+
+                WSReply::Ack
+            } else {
+                WSReply::Error(String::from("Nothing to undo"))
+            }
+        }
+        WSCommand::Redo => {
+            if lights.lock().await.redo(Source::WebSocket(peer)) {
+                info!("Redid change");
+
+                WSReply::Ack
+            } else {
+                WSReply::Error(String::from("Nothing to redo"))
+            }
+        }
+        WSCommand::Subscribe(topics) => {
+            subscriptions.subscribe(&topics);
+
+            WSReply::Ack
+        }
+        WSCommand::Unsubscribe(topics) => {
+            subscriptions.unsubscribe(&topics);
+
+            WSReply::Ack
+        }
+    }
+}
+
+async fn ws_message(
+    string: &str,
+    peer: SocketAddr,
+    scope: Scope,
+    lights: &SharedLights,
+    subscriptions: &mut WSSubscriptions,
+) -> Option<WSResponse> {
+    match serde_json::from_str::<WSRequest>(string) {
+        Ok(request) => Some(WSResponse {
+            id: request.id,
+            reply: ws_command(request.command, peer, scope, lights, subscriptions).await,
+        }),
+        Err(err) => match serde_json::from_str::<Color>(string) {
+            Ok(color) => {
+                // bare colors are the legacy protocol and are not acknowledged
+                if scope >= Scope::Color {
+                    info!(%color, "Set color");
+
+                    lights.lock().await.set(color, Source::WebSocket(peer));
+                } else {
+                    warn!("Ignoring WebSocket color from token without color scope");
+                }
+
+                None
+            }
+            Err(_err) => Some(WSResponse {
+                id: serde_json::from_str::<serde_json::Value>(string)
+                    .ok()
+                    .and_then(|value| value.get("id").cloned()),
+                reply: WSReply::Error(err.to_string()),
+            }),
+        },
+    }
+}
+
+#[instrument(name = "ws", skip_all, fields(%peer, ?scope))]
+async fn ws_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    scope: Scope,
+    lights: SharedLights,
+    metrics: SharedMetrics,
+) where
+    S: Stream<Item = Result<WSMessage, WSError>> + Sink<WSMessage, Error = WSError> + Unpin,
+{
+    metrics.ws_connected();
+
+    let (mut sender, mut receiver) = stream.split();
+
+    let mut subscriptions = WSSubscriptions { topics: None };
+
+    let (mut changes, mut last_color) = {
+        let lights = lights.lock().await;
+
+        (lights.subscribe(), lights.get())
+    };
+
+    match sender
+        .send(WSMessage::Text(serde_json::to_string(&last_color).unwrap()))
+        .await
+    {
+        Ok(_) => {}
+        Err(err) => {
+            warn!(error = %err, "Failed to send color to WebSocket");
+        }
+    }
+
+    'connection: loop {
+        tokio::select! {
+            message = receiver.next() => {
+                match message {
+                    Some(Ok(WSMessage::Text(string))) => {
+                        if let Some(response) = ws_message(&string, peer, scope, &lights, &mut subscriptions).await {
+                            if let WSReply::Error(err) = &response.reply {
+                                warn!(error = %err, "Failed to handle message from WebSocket");
+                            }
+
+                            match sender.send(WSMessage::Text(serde_json::to_string(&response).unwrap())).await {
+                                Ok(_) => {},
+                                Err(err) => {
+                                    warn!(error = %err, "Failed to send reply to WebSocket");
+                                    break;
+                                }
+                            }
+                        }
+                    },
+                    Some(Ok(WSMessage::Close(_frame))) => {
+                        break;
+                    },
+                    Some(Ok(_)) => {
+                        // ignore other message types
+                    },
+                    Some(Err(WSError::Protocol(WSProtocolError::ResetWithoutClosingHandshake))) => {
+                        // resets seem to be common for browsers
+                        break;
+                    },
+                    Some(Err(err)) => {
+                        warn!(error = %err, "Failed to poll WebSocket connection");
+                        break;
+                    },
+                    None => {
+                        break;
+                    }
+                }
+            }
+
+            change = changes.recv() => {
+                let (color, pattern) = match change {
+                    Ok(Change::Pattern { pattern, color }) => (color, Some(pattern)),
+                    Ok(Change::Frame { color }) => (color, None),
+                    Err(RecvError::Lagged(_skipped)) => {
+                        // too far behind to replay changes so resynchronize with the current state
+                        let lights = lights.lock().await;
+
+                        (lights.get(), Some(lights.get_pattern().clone()))
+                    },
+                    Err(RecvError::Closed) => {
+                        break 'connection;
+                    },
+                };
+
+                let messages = subscriptions.messages(
+                    Some(color).filter(|color| *color != last_color),
+                    pattern.as_ref(),
+                );
+
+                last_color = color;
+
+                for string in messages {
+                    match sender.send(WSMessage::Text(string)).await {
+                        Ok(_) => {},
+                        Err(err) => {
+                            warn!(error = %err, "Failed to send update to WebSocket");
+                            break 'connection;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    match sender.close().await {
+        Ok(()) => {}
+        Err(err) => {
+            warn!(error = %err, "Failed to close WebSocket connection");
+        }
+    }
+
+    metrics.ws_disconnected();
+}
+
+async fn ws_accept<S>(
+    socket: S,
+    peer: SocketAddr,
+    tokens: Tokens,
+    lights: SharedLights,
+    metrics: SharedMetrics,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut scope = Scope::Read;
+
+    // the same tokens as the http routes are checked during the handshake, and the large error
+    // type is the response tungstenite expects
+    #[allow(clippy::result_large_err)]
+    let authorize = |request: &WSHandshakeRequest, response: WSHandshakeResponse| {
+        let token = auth::bearer(
+            request
+                .headers()
+                .get("Authorization")
+                .and_then(|header| header.to_str().ok()),
+            request.uri().query(),
+        );
+
+        match tokens.authorize(token.as_deref(), Scope::Read) {
+            Ok(authorized) => {
+                scope = authorized;
+
+                Ok(response)
+            }
+            Err(status) => {
+                let mut error = WSErrorResponse::new(Some(String::from(status.reason_lossy())));
+                *error.status_mut() = status.code.try_into().unwrap();
+
+                Err(error)
+            }
+        }
+    };
+
+    match tokio_tungstenite::accept_hdr_async(socket, authorize).await {
+        Ok(stream) => {
+            ws_connection(stream, peer, scope, lights, metrics).await;
+        }
+        Err(err) => {
+            warn!(%peer, error = %err, "Failed to accept WebSocket connection");
+        }
+    }
+}
+
+pub async fn ws_server(tokens: Tokens, lights: SharedLights, metrics: SharedMetrics) {
+    let address = match env::var("WS_ADDRESS") {
+        Ok(val) => val,
+        Err(_err) => String::from(if cfg!(debug_assertions) {
+            "127.0.0.1"
+        } else {
+            "0.0.0.0"
+        }),
+    };
+
+    let port: u16 = match env::var("WS_PORT") {
+        Ok(val) => val.parse().unwrap(),
+        Err(_err) => 8001,
+    };
+
+    let acceptor = tls::tls_acceptor();
+
+    let listener = TcpListener::bind((address, port))
+        .await
+        .expect("Failed to bind TCP WebSocket address");
+
+    println!(
+        "{}{} {}",
+        Paint::masked("🕸  "),
+        Paint::default("WebSocket server started on").bold(),
+        Paint::default(
+            String::from(if acceptor.is_some() {
+                "wss://"
+            } else {
+                "ws://"
+            }) + &listener.local_addr().unwrap().to_string()
+        )
+        .bold()
+        .underline()
+    );
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                let acceptor_conn = acceptor.clone();
+                let tokens_conn = tokens.clone();
+                let lights_conn = Arc::clone(&lights);
+                let metrics_conn = Arc::clone(&metrics);
+
+                tokio::spawn(async move {
+                    match acceptor_conn {
+                        Some(acceptor) => match acceptor.accept(socket).await {
+                            Ok(socket) => {
+                                ws_accept(socket, peer, tokens_conn, lights_conn, metrics_conn)
+                                    .await;
+                            }
+                            Err(err) => {
+                                warn!(%peer, error = %err, "Failed TLS handshake");
+                            }
+                        },
+                        None => {
+                            ws_accept(socket, peer, tokens_conn, lights_conn, metrics_conn).await;
+                        }
+                    }
+                });
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to accept WebSocket connection");
+            }
+        }
+    }
+}